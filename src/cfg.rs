@@ -0,0 +1,453 @@
+use crate::expuncher::ReplacementSpan;
+use crate::module::item_attributes;
+use proc_macro2::LineColumn;
+use quote::ToTokens;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::{
+    self, Arm, AttrStyle, Attribute, Expr, ExprStruct, FieldsNamed, FieldsUnnamed, ImplItem, Item,
+    ItemEnum, Lit, Meta, NestedMeta, Stmt, Token, TraitItem,
+};
+
+/// `cfg`属性の評価に用いる設定の集合
+///
+/// フィーチャーと集合に名前が現れる設定のうち、集合に含まれないものを無効として扱う。
+/// `unix`や`target_os`などの名前が現れない設定は評価せず、それを含む属性はそのまま残す
+#[derive(Debug, Clone, Default)]
+pub struct CfgSet {
+    options: Vec<(String, Option<String>)>,
+}
+
+impl CfgSet {
+    pub fn new() -> CfgSet {
+        CfgSet {
+            options: Vec::new(),
+        }
+    }
+
+    /// `name`または`name="value"`の形式の設定を追加する
+    pub fn insert_spec(&mut self, spec: &str) {
+        match spec.split_once('=') {
+            Some((name, value)) => {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                self.insert(name.trim(), Some(value));
+            }
+            None => self.insert(spec.trim(), None),
+        }
+    }
+
+    /// カンマまたは空白区切りのフィーチャーを`feature="..."`として追加する
+    pub fn insert_features(&mut self, features: &str) {
+        for feature in features
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|feature| !feature.is_empty())
+        {
+            self.insert("feature", Some(feature));
+        }
+    }
+
+    /// 設定を追加する
+    pub fn insert(&mut self, name: &str, value: Option<&str>) {
+        let option = (String::from(name), value.map(String::from));
+        if !self.options.contains(&option) {
+            self.options.push(option);
+        }
+    }
+
+    /// 設定が有効か
+    pub fn contains(&self, name: &str, value: Option<&str>) -> bool {
+        self.options.iter().any(|(option_name, option_value)| {
+            option_name == name && option_value.as_deref() == value
+        })
+    }
+
+    /// 設定を評価できるか
+    ///
+    /// フィーチャーはCargoと同様に指定されたものだけが有効なので常に評価できる
+    fn is_known(&self, name: &str) -> bool {
+        name == "feature"
+            || self
+                .options
+                .iter()
+                .any(|(option_name, _)| option_name == name)
+    }
+
+    /// 設定が有効かを評価する
+    ///
+    /// 名前が集合に現れない場合は`None`を返す
+    fn evaluate_option(&self, name: &str, value: Option<&str>) -> Option<bool> {
+        self.is_known(name).then(|| self.contains(name, value))
+    }
+
+    /// `cfg`の述語を評価する
+    ///
+    /// 評価できない設定により真偽が定まらない場合は`None`を返す
+    pub fn evaluate(&self, predicate: &NestedMeta) -> Option<bool> {
+        match predicate {
+            NestedMeta::Meta(Meta::Path(path)) => {
+                self.evaluate_option(&path.get_ident()?.to_string(), None)
+            }
+            NestedMeta::Meta(Meta::NameValue(meta)) => match (&meta.lit, meta.path.get_ident()) {
+                (Lit::Str(lit), Some(ident)) => {
+                    self.evaluate_option(&ident.to_string(), Some(&lit.value()))
+                }
+                _ => None,
+            },
+            NestedMeta::Meta(Meta::List(list)) => {
+                let results: Vec<Option<bool>> = list
+                    .nested
+                    .iter()
+                    .map(|nested| self.evaluate(nested))
+                    .collect();
+                if list.path.is_ident("all") {
+                    if results.contains(&Some(false)) {
+                        Some(false)
+                    } else if results.contains(&None) {
+                        None
+                    } else {
+                        Some(true)
+                    }
+                } else if list.path.is_ident("any") {
+                    if results.contains(&Some(true)) {
+                        Some(true)
+                    } else if results.contains(&None) {
+                        None
+                    } else {
+                        Some(false)
+                    }
+                } else if list.path.is_ident("not") && results.len() == 1 {
+                    results[0].map(|result| !result)
+                } else {
+                    None
+                }
+            }
+            NestedMeta::Lit(_) => None,
+        }
+    }
+
+    /// `cfg(...)`の形式のメタ情報の述語を評価する
+    ///
+    /// `cfg`以外のメタ情報や評価できない述語の場合は`None`を返す
+    fn evaluate_cfg(&self, meta: &Meta) -> Option<bool> {
+        match meta {
+            Meta::List(list) if list.path.is_ident("cfg") && list.nested.len() == 1 => {
+                self.evaluate(list.nested.first()?)
+            }
+            _ => None,
+        }
+    }
+
+    /// `cfg_attr`を展開した属性の一覧を取得する
+    ///
+    /// 述語が偽となる`cfg_attr`の属性と、メタ情報として解析できない属性は除外する。
+    /// 述語を評価できない`cfg_attr`は展開せずにそのまま含める
+    pub fn expand_attributes(&self, attrs: &[Attribute]) -> Vec<Meta> {
+        let mut metas = Vec::new();
+        for attr in attrs {
+            if let Ok(meta) = attr.parse_meta() {
+                self.expand_meta(meta, &mut metas);
+            }
+        }
+        metas
+    }
+
+    fn expand_meta(&self, meta: Meta, metas: &mut Vec<Meta>) {
+        match meta {
+            Meta::List(list) if list.path.is_ident("cfg_attr") => {
+                let predicate = match list.nested.first() {
+                    Some(predicate) => self.evaluate(predicate),
+                    None => return,
+                };
+                match predicate {
+                    Some(true) => {
+                        for nested_meta in list.nested.into_iter().skip(1) {
+                            if let NestedMeta::Meta(meta) = nested_meta {
+                                self.expand_meta(meta, metas);
+                            }
+                        }
+                    }
+                    Some(false) => {}
+                    None => metas.push(Meta::List(list)),
+                }
+            }
+            _ => metas.push(meta),
+        }
+    }
+
+    /// 偽となる`cfg`属性を持たないか
+    pub fn is_enabled(&self, attrs: &[Attribute]) -> bool {
+        self.expand_attributes(attrs)
+            .iter()
+            .all(|meta| self.evaluate_cfg(meta) != Some(false))
+    }
+
+    /// `cfg_attr`を展開して`#[path = "..."]`属性で指定されたパスを取得する
+    pub fn path_attribute(&self, attrs: &[Attribute]) -> Option<String> {
+        self.expand_attributes(attrs)
+            .into_iter()
+            .find_map(|meta| match meta {
+                Meta::NameValue(meta) if meta.path.is_ident("path") => match meta.lit {
+                    Lit::Str(lit) => Some(lit.value()),
+                    _ => None,
+                },
+                _ => None,
+            })
+    }
+
+    /// 評価済みの`cfg`属性と`cfg_attr`属性を置き換える属性を取得する
+    ///
+    /// 評価できた`cfg`属性は削除し、`cfg_attr`属性は述語が真の場合に限り展開する。
+    /// 展開した属性は元の属性と同じく外部属性または内部属性とする。
+    /// それ以外の属性と述語を評価できない属性は置き換えないので`None`を返す
+    pub fn resolve_attribute(&self, attr: &Attribute) -> Option<Vec<String>> {
+        let bang = match attr.style {
+            AttrStyle::Outer => "",
+            AttrStyle::Inner(_) => "!",
+        };
+        self.resolve_meta(attr).map(|metas| {
+            metas
+                .iter()
+                .map(|meta| format!("#{}[{}]", bang, meta.to_token_stream()))
+                .collect()
+        })
+    }
+
+    /// 展開後のモジュールに引き継ぐ属性を取得する
+    ///
+    /// `cfg_attr`は展開し、評価済みの`cfg`属性と`#[path]`属性は除外する
+    pub fn inherited_attributes(&self, attrs: &[Attribute]) -> Vec<String> {
+        let mut inherited = Vec::new();
+        for attr in attrs.iter().filter(|attr| !attr.path.is_ident("path")) {
+            match self.resolve_meta(attr) {
+                Some(metas) => inherited.extend(
+                    metas
+                        .iter()
+                        .filter(|meta| !meta.path().is_ident("path"))
+                        .map(|meta| format!("#[{}]", meta.to_token_stream())),
+                ),
+                None => inherited.push(attr.to_token_stream().to_string()),
+            }
+        }
+        inherited
+    }
+
+    fn resolve_meta(&self, attr: &Attribute) -> Option<Vec<Meta>> {
+        if !attr.path.is_ident("cfg") && !attr.path.is_ident("cfg_attr") {
+            return None;
+        }
+        let meta = attr.parse_meta().ok()?;
+        if attr.path.is_ident("cfg") {
+            self.evaluate_cfg(&meta).map(|_| Vec::new())
+        } else {
+            // 述語を評価できない場合は属性を残す
+            let predicate = match &meta {
+                Meta::List(list) => list.nested.first(),
+                _ => None,
+            };
+            self.evaluate(predicate?)?;
+            let mut metas = Vec::new();
+            self.expand_meta(meta, &mut metas);
+            metas.retain(|meta| self.evaluate_cfg(meta).is_none());
+            Some(metas)
+        }
+    }
+}
+
+/// アイテムの内部で属性を持つ位置の`cfg`属性と`cfg_attr`属性を評価する
+///
+/// implとトレイトのアイテム、フィールド、列挙子、構造体式のフィールド、matchのアーム、文を対象とし、
+/// 述語が偽となるものを削除する置換と、それ以外の評価済みの属性を置き換える置換を収集する
+pub struct NestedCfgResolver<'a> {
+    cfg: &'a CfgSet,
+    replacement_spans: Vec<ReplacementSpan>,
+}
+
+impl<'a> NestedCfgResolver<'a> {
+    /// アイテムの内部の置換を収集する
+    ///
+    /// アイテム自体の属性は評価しない
+    pub fn resolve(cfg: &'a CfgSet, item: &Item) -> Vec<ReplacementSpan> {
+        let mut resolver = NestedCfgResolver {
+            cfg,
+            replacement_spans: Vec::new(),
+        };
+        resolver.visit_item(item);
+        resolver.replacement_spans
+    }
+
+    /// 属性を評価して置換を追加する
+    ///
+    /// 述語が偽となる場合は`start`から`end`までを削除して`false`を返す
+    fn resolve_attributes(
+        &mut self,
+        attrs: &[Attribute],
+        start: LineColumn,
+        end: LineColumn,
+    ) -> bool {
+        if !self.cfg.is_enabled(attrs) {
+            self.replacement_spans
+                .push(ReplacementSpan::removal(start, end));
+            return false;
+        }
+        for attr in attrs {
+            if let Some(resolved) = self.cfg.resolve_attribute(attr) {
+                let span = attr.span();
+                self.replacement_spans.push(ReplacementSpan::new(
+                    span.start(),
+                    span.end(),
+                    resolved.join(" "),
+                ));
+            }
+        }
+        true
+    }
+
+    /// カンマ区切りの要素の属性を評価する
+    ///
+    /// 要素を削除する場合は直後のカンマも含めて削除する
+    fn resolve_element<T: Spanned>(
+        &mut self,
+        attrs: &[Attribute],
+        element: &T,
+        comma: Option<&Token![,]>,
+    ) -> bool {
+        let span = element.span();
+        let end = comma.map_or(span.end(), |comma| comma.span().end());
+        self.resolve_attributes(attrs, span.start(), end)
+    }
+}
+
+impl<'ast, 'a> Visit<'ast> for NestedCfgResolver<'a> {
+    fn visit_impl_item(&mut self, impl_item: &'ast ImplItem) {
+        let attrs = match impl_item {
+            ImplItem::Const(item) => &item.attrs,
+            ImplItem::Method(item) => &item.attrs,
+            ImplItem::Type(item) => &item.attrs,
+            ImplItem::Macro(item) => &item.attrs,
+            _ => return visit::visit_impl_item(self, impl_item),
+        };
+        let span = impl_item.span();
+        if self.resolve_attributes(attrs, span.start(), span.end()) {
+            visit::visit_impl_item(self, impl_item);
+        }
+    }
+
+    fn visit_trait_item(&mut self, trait_item: &'ast TraitItem) {
+        let attrs = match trait_item {
+            TraitItem::Const(item) => &item.attrs,
+            TraitItem::Method(item) => &item.attrs,
+            TraitItem::Type(item) => &item.attrs,
+            TraitItem::Macro(item) => &item.attrs,
+            _ => return visit::visit_trait_item(self, trait_item),
+        };
+        let span = trait_item.span();
+        if self.resolve_attributes(attrs, span.start(), span.end()) {
+            visit::visit_trait_item(self, trait_item);
+        }
+    }
+
+    fn visit_fields_named(&mut self, fields: &'ast FieldsNamed) {
+        for pair in fields.named.pairs() {
+            let field = pair.value();
+            if self.resolve_element(&field.attrs, *field, pair.punct().copied()) {
+                self.visit_field(field);
+            }
+        }
+    }
+
+    fn visit_fields_unnamed(&mut self, fields: &'ast FieldsUnnamed) {
+        for pair in fields.unnamed.pairs() {
+            let field = pair.value();
+            if self.resolve_element(&field.attrs, *field, pair.punct().copied()) {
+                self.visit_field(field);
+            }
+        }
+    }
+
+    fn visit_item_enum(&mut self, item_enum: &'ast ItemEnum) {
+        for pair in item_enum.variants.pairs() {
+            let variant = pair.value();
+            if self.resolve_element(&variant.attrs, *variant, pair.punct().copied()) {
+                self.visit_variant(variant);
+            }
+        }
+    }
+
+    fn visit_expr_struct(&mut self, expr_struct: &'ast ExprStruct) {
+        self.visit_path(&expr_struct.path);
+        for pair in expr_struct.fields.pairs() {
+            let field_value = pair.value();
+            if self.resolve_element(&field_value.attrs, *field_value, pair.punct().copied()) {
+                self.visit_field_value(field_value);
+            }
+        }
+        if let Some(rest) = &expr_struct.rest {
+            self.visit_expr(rest);
+        }
+    }
+
+    fn visit_arm(&mut self, arm: &'ast Arm) {
+        // アームの末尾のカンマはアームに含まれる
+        let span = arm.span();
+        if self.resolve_attributes(&arm.attrs, span.start(), span.end()) {
+            visit::visit_arm(self, arm);
+        }
+    }
+
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+        // use文の属性はuse文全体の置換で置き換えるので、述語が偽の場合の削除のみを行う
+        if let Stmt::Item(Item::Use(item_use)) = stmt {
+            if !self.cfg.is_enabled(&item_use.attrs) {
+                let span = stmt.span();
+                self.replacement_spans
+                    .push(ReplacementSpan::removal(span.start(), span.end()));
+            }
+            return;
+        }
+
+        let attrs = match stmt {
+            Stmt::Local(local) => &local.attrs,
+            Stmt::Item(item) => item_attributes(item),
+            Stmt::Expr(expr) | Stmt::Semi(expr, _) => expr_attributes(expr),
+        };
+        let span = stmt.span();
+        if self.resolve_attributes(attrs, span.start(), span.end()) {
+            visit::visit_stmt(self, stmt);
+        }
+    }
+}
+
+/// 文として現れる式の属性を取得する
+///
+/// synは代入や二項演算の文の属性を最も左の被演算子に付与するので左辺を辿る
+fn expr_attributes(expr: &Expr) -> &[Attribute] {
+    match expr {
+        Expr::Assign(expr) => expr_attributes(&expr.left),
+        Expr::AssignOp(expr) => expr_attributes(&expr.left),
+        Expr::Binary(expr) => expr_attributes(&expr.left),
+        Expr::Block(expr) => &expr.attrs,
+        Expr::Break(expr) => &expr.attrs,
+        Expr::Call(expr) => &expr.attrs,
+        Expr::Continue(expr) => &expr.attrs,
+        Expr::Field(expr) => &expr.attrs,
+        Expr::ForLoop(expr) => &expr.attrs,
+        Expr::If(expr) => &expr.attrs,
+        Expr::Index(expr) => &expr.attrs,
+        Expr::Lit(expr) => &expr.attrs,
+        Expr::Loop(expr) => &expr.attrs,
+        Expr::Macro(expr) => &expr.attrs,
+        Expr::Match(expr) => &expr.attrs,
+        Expr::MethodCall(expr) => &expr.attrs,
+        Expr::Paren(expr) => &expr.attrs,
+        Expr::Path(expr) => &expr.attrs,
+        Expr::Return(expr) => &expr.attrs,
+        Expr::Unary(expr) => &expr.attrs,
+        Expr::Unsafe(expr) => &expr.attrs,
+        Expr::While(expr) => &expr.attrs,
+        _ => &[],
+    }
+}
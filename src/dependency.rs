@@ -0,0 +1,210 @@
+use crate::module::{defined_names, use_names, Library};
+use proc_macro2::{Ident, TokenStream, TokenTree};
+use syn::visit::{self, Visit};
+use syn::{Block, Item, ItemMod, ItemUse, Macro, Path, Stmt, UseTree};
+
+/// 入れ子になった位置に現れる依存関係の種類
+#[derive(Debug)]
+pub enum DependencyKind<'ast> {
+    /// use文
+    Use(&'ast ItemUse),
+    /// 式や型、マクロに現れるパスのセグメント
+    Path {
+        segments: Vec<Ident>,
+        /// マクロ定義中の`$crate`から始まるパスか
+        is_dollar_crate: bool,
+    },
+}
+
+/// 入れ子になった位置に現れる依存関係
+#[derive(Debug)]
+pub struct NestedDependency<'ast> {
+    pub kind: DependencyKind<'ast>,
+    /// 依存関係を囲むインラインモジュールのパス（関数本体などは含まない）
+    pub inline_parts: Vec<String>,
+}
+
+/// 関数本体やimpl、インラインモジュールなどの内部に現れる依存関係を出現順に収集する
+///
+/// パスはライブラリクレートの名前または`crate`から始まるもののみを収集する。
+/// ただし、ライブラリクレートと同じ名前がuse文やアイテムで定義されている場合は収集しない
+#[derive(Debug)]
+pub struct DependencyCollector<'ast, 'a> {
+    libraries: &'a [Library],
+    inline_parts: Vec<String>,
+    /// 走査中の位置でuse文やアイテムにより定義されている名前
+    bound_names: Vec<String>,
+    dependencies: Vec<NestedDependency<'ast>>,
+}
+
+impl<'ast, 'a> DependencyCollector<'ast, 'a> {
+    /// アイテムの内部に現れる依存関係を収集する
+    ///
+    /// アイテム自体がuse文の場合は収集しない
+    ///
+    /// # Arguments
+    ///
+    /// * `libraries` 名前で参照できるライブラリクレート
+    ///
+    /// * `bound_names` アイテムを含むモジュールで定義されている名前
+    pub fn collect(
+        item: &'ast Item,
+        libraries: &'a [Library],
+        bound_names: &[String],
+    ) -> Vec<NestedDependency<'ast>> {
+        let mut collector = DependencyCollector {
+            libraries,
+            inline_parts: Vec::new(),
+            bound_names: bound_names.to_vec(),
+            dependencies: Vec::new(),
+        };
+        if !matches!(item, Item::Use(_)) {
+            collector.visit_item(item);
+        }
+        collector.dependencies
+    }
+
+    /// パスのセグメントが収集の対象であれば登録する
+    fn push_path(&mut self, segments: Vec<Ident>, is_dollar_crate: bool) {
+        let is_target = match segments.first() {
+            Some(first) => {
+                first == "crate"
+                    || (self.libraries.iter().any(|library| first == &library.name)
+                        && !self.bound_names.iter().any(|name| first == name))
+            }
+            None => false,
+        };
+        if is_target && segments.len() > 1 {
+            self.dependencies.push(NestedDependency {
+                kind: DependencyKind::Path {
+                    segments,
+                    is_dollar_crate,
+                },
+                inline_parts: self.inline_parts.clone(),
+            });
+        }
+    }
+
+    /// マクロの引数のトークン列から`a::b::c`の形のパスを探す
+    fn visit_tokens(&mut self, tokens: TokenStream) {
+        let mut segments: Vec<Ident> = Vec::new();
+        // 直前のトークンが`::`の途中または直後か
+        let mut colons = 0;
+        // 直前のトークンが`$`か
+        let mut dollar = false;
+        // 収集中のパスが`$crate`から始まるか
+        let mut is_dollar_crate = false;
+
+        for token in tokens {
+            match token {
+                TokenTree::Ident(ident) => {
+                    if colons != 2 {
+                        self.push_path(std::mem::take(&mut segments), is_dollar_crate);
+                        is_dollar_crate = dollar;
+                    }
+                    segments.push(ident);
+                    colons = 0;
+                    dollar = false;
+                    continue;
+                }
+                TokenTree::Punct(punct) if punct.as_char() == ':' && !segments.is_empty() => {
+                    colons += 1;
+                }
+                TokenTree::Group(group) => {
+                    self.push_path(std::mem::take(&mut segments), is_dollar_crate);
+                    colons = 0;
+                    self.visit_tokens(group.stream());
+                }
+                TokenTree::Punct(punct) => {
+                    self.push_path(std::mem::take(&mut segments), is_dollar_crate);
+                    colons = 0;
+                    dollar = punct.as_char() == '$';
+                    continue;
+                }
+                _ => {
+                    self.push_path(std::mem::take(&mut segments), is_dollar_crate);
+                    colons = 0;
+                }
+            }
+            dollar = false;
+        }
+        self.push_path(segments, is_dollar_crate);
+    }
+}
+
+impl<'ast, 'a> Visit<'ast> for DependencyCollector<'ast, 'a> {
+    fn visit_item_use(&mut self, item_use: &'ast ItemUse) {
+        self.dependencies.push(NestedDependency {
+            kind: DependencyKind::Use(item_use),
+            inline_parts: self.inline_parts.clone(),
+        });
+    }
+
+    fn visit_item_mod(&mut self, item_mod: &'ast ItemMod) {
+        // インラインモジュールの内部では外側のモジュールの名前は見えない
+        let outer_names = std::mem::replace(
+            &mut self.bound_names,
+            match &item_mod.content {
+                Some((_, items)) => bound_names(items),
+                None => Vec::new(),
+            },
+        );
+        self.inline_parts.push(item_mod.ident.to_string());
+        visit::visit_item_mod(self, item_mod);
+        self.inline_parts.pop();
+        self.bound_names = outer_names;
+    }
+
+    fn visit_block(&mut self, block: &'ast Block) {
+        // ブロック内のアイテムは外側の名前を隠す
+        let items = block.stmts.iter().filter_map(|stmt| match stmt {
+            Stmt::Item(item) => Some(item),
+            _ => None,
+        });
+        let outer_len = self.bound_names.len();
+        self.bound_names.extend(bound_names(items));
+        visit::visit_block(self, block);
+        self.bound_names.truncate(outer_len);
+    }
+
+    fn visit_path(&mut self, path: &'ast Path) {
+        self.push_path(
+            path.segments
+                .iter()
+                .map(|segment| segment.ident.clone())
+                .collect(),
+            false,
+        );
+        visit::visit_path(self, path);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        visit::visit_macro(self, mac);
+        // マクロの引数は構文木として解析されないのでトークン列から探す
+        self.visit_tokens(mac.tokens.clone());
+    }
+}
+
+/// モジュールやブロックのアイテムとuse文によって定義される名前を取得する
+///
+/// `use example;`や`extern crate example;`のようにクレート自体を参照する名前と、
+/// グロブのuse文などで特定できない名前は含めない
+pub fn bound_names<'a>(items: impl IntoIterator<Item = &'a Item>) -> Vec<String> {
+    let mut names = Vec::new();
+    for item in items {
+        match item {
+            Item::Use(item_use) => {
+                if !matches!(item_use.tree, UseTree::Name(_)) {
+                    use_names(&item_use.tree, None, &mut names);
+                }
+            }
+            Item::ExternCrate(item_extern) if item_extern.rename.is_none() => {}
+            _ => names.extend(
+                defined_names(std::slice::from_ref(item))
+                    .into_iter()
+                    .flatten(),
+            ),
+        }
+    }
+    names
+}
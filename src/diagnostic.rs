@@ -0,0 +1,94 @@
+use proc_macro2::LineColumn;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// 展開処理で検出した警告
+///
+/// rustcと同様の形式でファイルの位置とソースコードの該当箇所を表示する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// 英語の警告文
+    pub message: String,
+    /// 日本語の警告文
+    pub note: String,
+    pub path: PathBuf,
+    pub start: LineColumn,
+    pub end: LineColumn,
+    /// 警告の位置を含む行のソースコード
+    pub source_line: String,
+}
+
+impl Diagnostic {
+    /// ソースコードの内容から該当行を取得して警告を作成する
+    pub fn new(
+        message: String,
+        note: String,
+        path: &Path,
+        content: &str,
+        start: LineColumn,
+        end: LineColumn,
+    ) -> Diagnostic {
+        Diagnostic {
+            message,
+            note,
+            path: path.to_path_buf(),
+            start,
+            end,
+            source_line: content
+                .lines()
+                .nth(start.line.saturating_sub(1))
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+}
+
+/// 表示のためにカレントディレクトリからの相対パスを取得する
+///
+/// カレントディレクトリの外にある場合はそのままのパスを返す
+pub fn relative_path(path: &Path) -> PathBuf {
+    env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = relative_path(&self.path);
+        let line_number = self.start.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        // 注：LineColumn::columnはUTF-8文字としてのカウントである
+        // タブなどの幅を揃えるため、先行する空白文字はそのまま用いる
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.start.column)
+            .map(|c| if c.is_whitespace() { c } else { ' ' })
+            .collect();
+        let line_length = self.source_line.chars().count();
+        let end_column = if self.end.line == self.start.line {
+            self.end.column.min(line_length)
+        } else {
+            line_length
+        };
+        let carets = "^".repeat(end_column.saturating_sub(self.start.column).max(1));
+
+        writeln!(f, "warning: {}", self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter,
+            path.display(),
+            self.start.line,
+            self.start.column + 1
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line_number, self.source_line)?;
+        writeln!(f, "{} | {}{}", gutter, indent, carets)?;
+        writeln!(f, "{} |", gutter)?;
+        write!(f, "{} = note: {}", gutter, self.note)
+    }
+}
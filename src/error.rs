@@ -0,0 +1,289 @@
+use proc_macro2::LineColumn;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
+
+/// 展開処理で発生するエラー
+#[derive(Debug)]
+pub enum ExpunchError {
+    /// ソースコードのファイルを開けなかった
+    FileNotFound {
+        path: PathBuf,
+        module_parts: Vec<String>,
+        source: io::Error,
+    },
+    /// ソースコードのファイルの読み取りに失敗した
+    ReadFailed {
+        path: PathBuf,
+        module_parts: Vec<String>,
+        source: io::Error,
+    },
+    /// ソースコードの構文解析に失敗した
+    ParseFailed {
+        path: PathBuf,
+        module_parts: Vec<String>,
+        source: syn::Error,
+    },
+    /// `super`などによりルートより上の階層へ遡ろうとした
+    AboveRoot {
+        path: PathBuf,
+        module_parts: Vec<String>,
+    },
+    /// 標準入力からのソースコードの読み取りに失敗した
+    StdinReadFailed { source: io::Error },
+    /// 整形のための展開したソースコードの構文解析に失敗した
+    FormatFailed { source: syn::Error },
+    /// ソースコードの置換がファイルの範囲外にある
+    ReplacementOutOfRange {
+        path: PathBuf,
+        module_parts: Vec<String>,
+        start: LineColumn,
+        end: LineColumn,
+    },
+    /// ソースコードの置換が互いに重なっている
+    OverlappingReplacements {
+        path: PathBuf,
+        module_parts: Vec<String>,
+        first: (LineColumn, LineColumn),
+        second: (LineColumn, LineColumn),
+    },
+    /// ソースマップのファイルの読み取りに失敗した
+    SourceMapReadFailed { path: PathBuf, source: io::Error },
+    /// ソースマップのファイルの内容が不正である
+    InvalidSourceMap {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// 展開したソースコードの行に対応する元のファイルの行が存在しない
+    LineNotMapped { line: usize },
+    /// 出力先のファイルの作成に失敗した
+    CreateFailed { path: PathBuf, source: io::Error },
+    /// 展開したソースコードの書き込みに失敗した
+    WriteFailed { source: io::Error },
+    /// 展開したソースコードがUTF-8として不正である
+    InvalidUtf8 { source: FromUtf8Error },
+    /// `cargo metadata`の実行に失敗した
+    MetadataFailed { source: cargo_metadata::Error },
+    /// 展開対象のパッケージが見つからなかった
+    PackageNotFound { name: Option<String> },
+    /// 展開するよう指定された依存クレートが存在しなかった
+    DependencyNotFound { name: String, package: String },
+    /// 指定されたターゲットがパッケージに存在しなかった
+    TargetNotFound {
+        kind: String,
+        name: String,
+        package: String,
+    },
+}
+
+impl ExpunchError {
+    /// エラーの原因となったファイルのパスを取得する
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            ExpunchError::FileNotFound { path, .. }
+            | ExpunchError::ReadFailed { path, .. }
+            | ExpunchError::ParseFailed { path, .. }
+            | ExpunchError::AboveRoot { path, .. }
+            | ExpunchError::ReplacementOutOfRange { path, .. }
+            | ExpunchError::OverlappingReplacements { path, .. }
+            | ExpunchError::SourceMapReadFailed { path, .. }
+            | ExpunchError::InvalidSourceMap { path, .. }
+            | ExpunchError::CreateFailed { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// エラーの原因となったモジュールのパスを取得する
+    pub fn module_parts(&self) -> Option<&[String]> {
+        match self {
+            ExpunchError::FileNotFound { module_parts, .. }
+            | ExpunchError::ReadFailed { module_parts, .. }
+            | ExpunchError::ParseFailed { module_parts, .. }
+            | ExpunchError::AboveRoot { module_parts, .. }
+            | ExpunchError::ReplacementOutOfRange { module_parts, .. }
+            | ExpunchError::OverlappingReplacements { module_parts, .. } => Some(module_parts),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExpunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpunchError::FileNotFound { path, .. } => write!(
+                f,
+                "File {0} not exists
+ファイル {0} が存在しません",
+                path.display()
+            ),
+            ExpunchError::ReadFailed { path, .. } => write!(
+                f,
+                "Failed to read the file {0}
+ファイル {0} の読み取りに失敗しました",
+                path.display()
+            ),
+            ExpunchError::ParseFailed { path, source, .. } => {
+                let start = source.span().start();
+                write!(
+                    f,
+                    "Failed to parse the source-code {0} at {1}:{2}: {3}
+ソースコード {0} の {1}:{2} の構文解析に失敗しました: {3}",
+                    path.display(),
+                    start.line,
+                    start.column + 1,
+                    source
+                )
+            }
+            ExpunchError::AboveRoot { path, .. } => write!(
+                f,
+                "Failed to get the parent directory of the {0}
+{0} より上の階層へ遡ろうとしました",
+                path.display()
+            ),
+            ExpunchError::ReplacementOutOfRange {
+                path, start, end, ..
+            } => write!(
+                f,
+                "Replacement {1}:{2}-{3}:{4} is out of the range of the source-code {0}
+ソースコード {0} の範囲外に置換 {1}:{2}-{3}:{4} が指定されました",
+                path.display(),
+                start.line,
+                start.column + 1,
+                end.line,
+                end.column + 1
+            ),
+            ExpunchError::OverlappingReplacements {
+                path,
+                first,
+                second,
+                ..
+            } => write!(
+                f,
+                "Replacements {1}:{2}-{3}:{4} and {5}:{6}-{7}:{8} of the source-code {0} overlap
+ソースコード {0} の置換 {1}:{2}-{3}:{4} と {5}:{6}-{7}:{8} が重なっています",
+                path.display(),
+                first.0.line,
+                first.0.column + 1,
+                first.1.line,
+                first.1.column + 1,
+                second.0.line,
+                second.0.column + 1,
+                second.1.line,
+                second.1.column + 1
+            ),
+            ExpunchError::SourceMapReadFailed { path, .. } => write!(
+                f,
+                "Failed to read the source map {0}
+ソースマップ {0} の読み取りに失敗しました",
+                path.display()
+            ),
+            ExpunchError::InvalidSourceMap { path, source } => write!(
+                f,
+                "Invalid source map {0}: {1}
+ソースマップ {0} の内容が不正です: {1}",
+                path.display(),
+                source
+            ),
+            ExpunchError::LineNotMapped { line } => write!(
+                f,
+                "Line {0} of the expanded source-code has no original line; it is generated by expunch or out of range
+展開したソースコードの {0} 行目に対応する元のファイルの行がありません。expunchが生成した行か範囲外の行です",
+                line
+            ),
+            ExpunchError::StdinReadFailed { .. } => write!(
+                f,
+                "Failed to read the source-code from the standard input
+標準入力からのソースコードの読み取りに失敗しました"
+            ),
+            ExpunchError::FormatFailed { source } => {
+                let start = source.span().start();
+                write!(
+                    f,
+                    "Failed to parse the expanded source-code to format it at {0}:{1}: {2}
+整形のための展開したソースコードの {0}:{1} の構文解析に失敗しました: {2}",
+                    start.line,
+                    start.column + 1,
+                    source
+                )
+            }
+            ExpunchError::CreateFailed { path, .. } => write!(
+                f,
+                "Failed to create the file {0}
+ファイル {0} の作成に失敗しました",
+                path.display()
+            ),
+            ExpunchError::WriteFailed { .. } => write!(
+                f,
+                "Failed to write the expanded source-code
+展開したソースコードの書き込みに失敗しました"
+            ),
+            ExpunchError::InvalidUtf8 { .. } => write!(
+                f,
+                "The expanded source-code is not valid UTF-8
+展開したソースコードがUTF-8として不正です"
+            ),
+            ExpunchError::MetadataFailed { source } => write!(
+                f,
+                "Failed to get the metadata of the workspace: {0}
+ワークスペースのメタデータの取得に失敗しました: {0}",
+                source
+            ),
+            ExpunchError::PackageNotFound { name: Some(name) } => write!(
+                f,
+                "Package `{0}` not found in the workspace
+パッケージ `{0}` がワークスペースに存在しません",
+                name
+            ),
+            ExpunchError::PackageNotFound { name: None } => write!(
+                f,
+                "Root package not found; specify a package with `--package`
+ルートパッケージが存在しません。`--package` でパッケージを指定してください"
+            ),
+            ExpunchError::DependencyNotFound { name, package } => write!(
+                f,
+                "Dependency `{0}` not found in the dependencies of the package `{1}`
+パッケージ `{1}` の依存関係に依存クレート `{0}` が存在しません",
+                name, package
+            ),
+            ExpunchError::TargetNotFound {
+                kind,
+                name,
+                package,
+            } => write!(
+                f,
+                "Target `{0}` of the kind `{1}` not found in the package `{2}`
+パッケージ `{2}` に種類 `{1}` のターゲット `{0}` が存在しません",
+                name, kind, package
+            ),
+        }
+    }
+}
+
+impl Error for ExpunchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExpunchError::FileNotFound { source, .. } | ExpunchError::ReadFailed { source, .. } => {
+                Some(source)
+            }
+            ExpunchError::ParseFailed { source, .. } | ExpunchError::FormatFailed { source } => {
+                Some(source)
+            }
+            ExpunchError::StdinReadFailed { source }
+            | ExpunchError::SourceMapReadFailed { source, .. }
+            | ExpunchError::CreateFailed { source, .. }
+            | ExpunchError::WriteFailed { source } => Some(source),
+            ExpunchError::InvalidUtf8 { source } => Some(source),
+            ExpunchError::InvalidSourceMap { source, .. } => Some(source),
+            ExpunchError::MetadataFailed { source } => Some(source),
+            ExpunchError::AboveRoot { .. }
+            | ExpunchError::ReplacementOutOfRange { .. }
+            | ExpunchError::OverlappingReplacements { .. }
+            | ExpunchError::LineNotMapped { .. }
+            | ExpunchError::PackageNotFound { .. }
+            | ExpunchError::DependencyNotFound { .. }
+            | ExpunchError::TargetNotFound { .. } => None,
+        }
+    }
+}
@@ -1,17 +1,130 @@
+use crate::cfg::{CfgSet, NestedCfgResolver};
+use crate::dependency::{bound_names, DependencyCollector, DependencyKind};
+use crate::diagnostic::Diagnostic;
+use crate::error::ExpunchError;
 use crate::module::*;
-use proc_macro2::LineColumn;
+use crate::shaker;
+use crate::source_map::{MappedWriter, SourceMap};
+use proc_macro2::{LineColumn, TokenStream, TokenTree};
 use quote::ToTokens;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, prelude::*, BufReader, Read};
-use std::path::PathBuf;
-use syn::{self, spanned::Spanned, Ident, Item, ItemUse, UseGroup, UseName, UsePath, UseTree};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use syn::{
+    self, spanned::Spanned, Attribute, Ident, Item, ItemMacro, ItemMod, ItemUse, LitStr, UseGroup,
+    UseName, UsePath, UseRename, UseTree,
+};
+
+/// 展開する子モジュールの出力順
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModuleOrder {
+    /// ソースコード中で最初に参照または宣言された順
+    #[default]
+    Declaration,
+    /// モジュール名のアルファベット順
+    Alphabetical,
+}
+
+/// 展開したソースコードの整形方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Formatting {
+    /// 整形しない
+    #[default]
+    None,
+    /// 展開したモジュールの内容を入れ子の深さに合わせて字下げする
+    Indent,
+    /// prettypleaseで全体を整形する
+    Pretty,
+}
+
+/// 展開したソースコードの改行文字
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+    /// 実行環境の標準（Windowsでは`\r\n`、それ以外では`\n`）
+    Native,
+}
+
+impl LineEnding {
+    /// 改行文字の文字列を取得する
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Native if cfg!(windows) => "\r\n",
+            LineEnding::Native => "\n",
+        }
+    }
+
+    /// 文字列中の`\r\n`と`\n`をすべてこの改行文字に統一する
+    pub fn normalize(&self, content: &str) -> String {
+        let mut normalized = String::with_capacity(content.len());
+        for line in content.split_inclusive('\n') {
+            let (line, line_ending) = split_line_ending(line);
+            normalized.push_str(line);
+            if !line_ending.is_empty() {
+                normalized.push_str(self.as_str());
+            }
+        }
+        normalized
+    }
+}
+
+/// 解析中のファイルに関する情報
+struct FileContext<'a> {
+    source_path: &'a PathBuf,
+    content: &'a str,
+    source_parts: &'a [String],
+    crate_name: &'a str,
+    crate_path: &'a PathBuf,
+}
+
+/// トップレベルのソースコードのモジュール直下のuse文
+#[derive(Debug)]
+struct RootUse {
+    start: LineColumn,
+    /// `cfg_attr`を展開した属性
+    resolved_attributes: Vec<String>,
+    /// 置換後のuse文
+    item_use: ItemUse,
+}
+
+/// `#[macro_export]`されたライブラリクレートのマクロ
+#[derive(Debug)]
+struct ExportedMacro {
+    /// マクロを定義したライブラリクレートの名前
+    library: String,
+    /// マクロを定義したファイルのモジュールのパス
+    module_parts: Vec<String>,
+    name: String,
+    start: LineColumn,
+    end: LineColumn,
+}
 
 #[derive(Debug)]
 pub struct Expuncher {
     collected_modules: ModuleNode,
-    package_name: String,
-    package_src_path: PathBuf,
+    /// 展開対象のライブラリクレート（先頭はパッケージ自身のライブラリクレート）
+    libraries: Vec<Library>,
+    /// ライブラリクレートごとに名前で参照できる依存クレート
+    library_dependencies: HashMap<String, Vec<String>>,
+    module_order: ModuleOrder,
+    formatting: Formatting,
+    line_ending: Option<LineEnding>,
+    tree_shaking: bool,
+    strip_tests: bool,
+    inline_includes: bool,
+    cfg: Option<CfgSet>,
+    exported_macros: Vec<ExportedMacro>,
+    root_uses: Vec<RootUse>,
+    diagnostics: Vec<Diagnostic>,
+    /// クレートのルート直下で解決できなかったライブラリクレートと名前、その警告（マクロの判定待ち）
+    unresolved_root_items: Vec<(String, String, Diagnostic)>,
+    /// モジュールのファイルごとに定義された名前のキャッシュ
+    defined_names: HashMap<PathBuf, Option<Vec<String>>>,
 }
 
 impl Expuncher {
@@ -19,32 +132,253 @@ impl Expuncher {
     ///
     /// # Arguments
     ///
-    /// * `package_name` モジュールの解決に用いるパッケージの名前
-    ///
-    /// * `package_src_path` パッケージのsrcディレクトリへのパス
+    /// * `crate_name` モジュールの解決に用いるパッケージのライブラリクレートの名前
     ///
-    /// * `crate_path` クレートのパス
-    pub fn new(package_name: &str, package_src_path: PathBuf) -> Expuncher {
+    /// * `lib_path` ライブラリクレートのルートのファイルへのパス
+    pub fn new(crate_name: &str, lib_path: PathBuf) -> Expuncher {
         Expuncher {
             collected_modules: ModuleNode::new(),
-            package_name: String::from(package_name),
-            package_src_path,
+            libraries: vec![Library::new(crate_name, lib_path)],
+            library_dependencies: HashMap::new(),
+            module_order: ModuleOrder::default(),
+            formatting: Formatting::default(),
+            line_ending: None,
+            tree_shaking: true,
+            strip_tests: true,
+            inline_includes: false,
+            cfg: None,
+            exported_macros: Vec::new(),
+            root_uses: Vec::new(),
+            diagnostics: Vec::new(),
+            unresolved_root_items: Vec::new(),
+            defined_names: HashMap::new(),
+        }
+    }
+
+    /// 展開対象のライブラリクレートを追加する
+    ///
+    /// 追加したライブラリクレートはそれぞれクレートのルート直下のモジュールとして展開する
+    ///
+    /// # Arguments
+    ///
+    /// * `name` ソースコード中でライブラリクレートを参照する名前
+    ///
+    /// * `lib_path` ライブラリクレートのルートのファイルへのパス
+    pub fn add_library(&mut self, name: &str, lib_path: PathBuf) {
+        if !self.is_library(name) {
+            self.libraries.push(Library::new(name, lib_path));
         }
     }
 
+    /// ライブラリクレートが名前で参照できる依存クレートを設定する
+    ///
+    /// 設定しない場合はすべてのライブラリクレートを参照できるものとする
+    ///
+    /// # Arguments
+    ///
+    /// * `name` ライブラリクレートの名前
+    ///
+    /// * `dependencies` ソースコード中で依存クレートを参照する名前
+    pub fn set_library_dependencies(&mut self, name: &str, dependencies: &[String]) {
+        self.library_dependencies.insert(
+            crate_ident(name),
+            dependencies.iter().map(|name| crate_ident(name)).collect(),
+        );
+    }
+
+    /// クレートのソースコードから名前で参照できるライブラリクレートを取得する
+    ///
+    /// トップレベルのソースコードからはパッケージ自身のライブラリクレートとその依存クレートを参照できる
+    fn visible_libraries(&self, crate_name: &str) -> Vec<Library> {
+        let is_root = crate_name == "crate";
+        let name = if is_root {
+            &self.libraries[0].name
+        } else {
+            crate_name
+        };
+        match self.library_dependencies.get(name) {
+            Some(dependencies) => self
+                .libraries
+                .iter()
+                .filter(|library| {
+                    dependencies.contains(&library.name) || (is_root && library.name == name)
+                })
+                .cloned()
+                .collect(),
+            None => self.libraries.clone(),
+        }
+    }
+
+    /// 展開対象のライブラリクレートの名前か
+    fn is_library(&self, name: &str) -> bool {
+        self.libraries.iter().any(|library| library.name == name)
+    }
+
+    /// モジュールのパスがライブラリクレートのルートを指すか
+    fn is_library_root(&self, module_parts: &[String]) -> bool {
+        module_parts.len() == 1 && self.is_library(&module_parts[0])
+    }
+
+    /// 展開する子モジュールの出力順を設定する
+    pub fn set_module_order(&mut self, module_order: ModuleOrder) {
+        self.module_order = module_order;
+    }
+
+    /// 展開したソースコードの整形方法を設定する
+    pub fn set_formatting(&mut self, formatting: Formatting) {
+        self.formatting = formatting;
+    }
+
+    /// 展開したソースコードの改行文字を統一するよう設定する
+    ///
+    /// 設定しない場合は各ファイルの改行文字をそのまま出力する
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = Some(line_ending);
+    }
+
+    /// ライブラリクレートの参照されないアイテムを取り除くかを設定する（既定は有効）
+    pub fn set_tree_shaking(&mut self, tree_shaking: bool) {
+        self.tree_shaking = tree_shaking;
+    }
+
+    /// `#[cfg(test)]`や`#[test]`が付与されたテスト専用のアイテムを取り除くかを設定する（既定は有効）
+    pub fn set_strip_tests(&mut self, strip_tests: bool) {
+        self.strip_tests = strip_tests;
+    }
+
+    /// `include!`、`include_str!`、`include_bytes!`をファイルの内容に置き換えるかを設定する（既定は無効）
+    pub fn set_inline_includes(&mut self, inline_includes: bool) {
+        self.inline_includes = inline_includes;
+    }
+
+    /// `cfg`属性と`cfg_attr`属性の評価に用いる設定の集合を設定する
+    ///
+    /// 設定した場合は述語が偽となるアイテムとmod宣言を取り除く
+    pub fn set_cfg(&mut self, cfg: CfgSet) {
+        self.cfg = Some(cfg);
+    }
+
+    /// ライブラリクレートのアイテムのうち、展開するソースコードから到達できないものを取り除く
+    pub fn shake_tree(&mut self) -> Result<(), ExpunchError> {
+        let library_parts: Vec<Vec<String>> = self
+            .libraries
+            .iter()
+            .map(|library| vec![library.name.clone()])
+            .collect();
+        shaker::shake(&mut self.collected_modules, &library_parts)
+    }
+
     /// ファイルの内容を基にすべての依存するモジュールを解析する
     ///
     /// # Arguments
     ///
     /// * `source_path` ソースコードへのパス
-    pub fn analyze_source_file(&mut self, source_path: &PathBuf) -> Result<(), String> {
+    pub fn analyze_source_file(&mut self, source_path: &PathBuf) -> Result<(), ExpunchError> {
+        self.collected_modules
+            .update(&[], source_path.clone(), Some("pub"));
+        self.analyze_root(source_path)
+    }
+
+    /// 標準入力などから読み込んだソースコードの内容を基にすべての依存するモジュールを解析する
+    ///
+    /// # Arguments
+    ///
+    /// * `source_path` mod宣言や`super`の解決とエラーの報告に用いるソースコードの仮のパス
+    ///
+    /// * `content` ソースコードの内容
+    pub fn analyze_source_code(
+        &mut self,
+        source_path: &PathBuf,
+        content: String,
+    ) -> Result<(), ExpunchError> {
+        self.collected_modules
+            .update(&[], source_path.clone(), Some("pub"));
+        self.collected_modules.content = Some(content);
+        self.analyze_root(source_path)
+    }
+
+    /// 登録済みのトップレベルのソースコードを基にすべての依存するモジュールを解析する
+    fn analyze_root(&mut self, source_path: &PathBuf) -> Result<(), ExpunchError> {
         let source_parts = Vec::new();
+        self.analyze_file_impl(
+            source_path,
+            &source_parts,
+            "crate",
+            source_path,
+            &make_module_dir(source_path, true),
+        )?;
+        if self.tree_shaking {
+            self.shake_tree()?;
+        }
+        self.reexport_macros();
+
+        // エクスポートされたマクロとして解決されたクレートのルートの名前の警告を取り消す
+        for (library, name, diagnostic) in std::mem::take(&mut self.unresolved_root_items) {
+            if self
+                .exported_macros
+                .iter()
+                .any(|exported| exported.library == library && exported.name == name)
+            {
+                self.diagnostics
+                    .retain(|registered| registered != &diagnostic);
+            }
+        }
+
         self.collected_modules
-            .update(&source_parts, source_path.clone(), Some("pub"));
+            .finalize_replacement_spans(&mut Vec::new())
+    }
 
-        self.analyze_file_impl(source_path, &source_parts, "crate", source_path)?;
-        self.collected_modules.sort_replacement_spans();
-        Ok(())
+    /// `#[macro_export]`されたマクロをライブラリクレートのモジュールから再エクスポートする
+    ///
+    /// エクスポートされたマクロは展開後のクレートのルートに置かれるので、
+    /// `use example::my_macro;`のような参照を解決できるようにする
+    fn reexport_macros(&mut self) {
+        let names: Vec<(String, String)> = self
+            .exported_macros
+            .iter()
+            .filter(|exported| {
+                self.collected_modules
+                    .node(&exported.module_parts)
+                    .is_some_and(|node| !node.is_removed(exported.start, exported.end))
+            })
+            .map(|exported| (exported.library.clone(), exported.name.clone()))
+            .collect();
+        if names.is_empty() {
+            return;
+        }
+
+        for (library, name) in &names {
+            if let Some(node) = self
+                .collected_modules
+                .node_mut(std::slice::from_ref(library))
+            {
+                let reexport = format!("pub use crate::{};", name);
+                if !node.appended_items.contains(&reexport) {
+                    node.appended_items.push(reexport);
+                }
+            }
+        }
+
+        // クレートのルートに置かれたマクロと同名のインポートを削除する
+        for root_use in &self.root_uses {
+            let use_tree = self.remove_exported_macros(&root_use.item_use.tree, &names);
+            if use_tree.as_ref() == Some(&root_use.item_use.tree) {
+                continue;
+            }
+            let item_use = use_tree.map(|tree| ItemUse {
+                tree,
+                ..root_use.item_use.clone()
+            });
+            if let Some(span) = self
+                .collected_modules
+                .replacement_spans
+                .iter_mut()
+                .find(|span| span.start == root_use.start)
+            {
+                span.replacement =
+                    make_use_replacement(&root_use.resolved_attributes, item_use.as_ref());
+            }
+        }
     }
 
     /// ファイルの内容を基にすべての依存するモジュールを解析する
@@ -58,216 +392,756 @@ impl Expuncher {
         &mut self,
         source_path: &PathBuf,
         source_parts: &[String],
-    ) -> Result<(), String> {
-        self.analyze_file_impl(source_path, source_parts, "crate", source_path)?;
-        self.collected_modules.sort_replacement_spans();
-        Ok(())
+    ) -> Result<(), ExpunchError> {
+        self.analyze_file_impl(
+            source_path,
+            source_parts,
+            "crate",
+            source_path,
+            &make_module_dir(source_path, false),
+        )?;
+        self.collected_modules
+            .finalize_replacement_spans(&mut Vec::new())
     }
 
+    /// # Arguments
+    ///
+    /// * `module_dir` ファイル中のmod宣言を解決するディレクトリ
     fn analyze_file_impl(
         &mut self,
         source_path: &PathBuf,
         source_parts: &[String],
         crate_name: &str,
         crate_path: &PathBuf,
-    ) -> Result<(), String> {
-        let mut file = File::open(source_path).or_else(|_| {
-            Err(format!(
-                "File {0} not exists
-ファイル {1} が存在しません",
-                source_path.to_str().unwrap_or("(undisplayable path)"),
-                source_path.to_str().unwrap_or("（表示できないパス）"),
-            )
-            .to_owned())
-        })?;
-        let mut content = String::new();
-        file.read_to_string(&mut content).or_else(|_| {
-            Err(format!(
-                "Failed to read the file {0}
-ファイル {1} の読み取りに失敗しました",
-                source_path.to_str().unwrap_or("(undisplayable path)"),
-                source_path.to_str().unwrap_or("（表示できないパス）"),
-            ))
-        })?;
-        let ast = syn::parse_file(&content).or_else(|_| {
-            Err(format!(
-                "Failed to parse the source-code {0}
-ソースコード {1} の構文解析に失敗しました",
-                source_path.to_str().unwrap_or("(undisplayable path)"),
-                source_path.to_str().unwrap_or("（表示できないパス）"),
-            ))
-        })?;
-
-        // selfパスの解決
-        let self_path = match source_path.file_name() {
-            Some(name) if name == "mod.rs" => source_path
-                .parent()
-                .ok_or_else(|| {
-                    format!(
-                        "Failed to get the parent directory of the {0}
-{1} より上の階層へ遡ろうとしました",
-                        source_path.to_str().unwrap_or("(undisplayable path)"),
-                        source_path.to_str().unwrap_or("（表示できないパス）"),
-                    )
-                })?
-                .to_path_buf(),
-            _ => source_path.clone(),
+        module_dir: &Path,
+    ) -> Result<(), ExpunchError> {
+        let content = match self.collected_modules.node(source_parts) {
+            Some(node) => node.read_source(source_path, source_parts)?,
+            None => read_source_file(source_path, source_parts)?,
         };
+        let ast = parse_source_code(&content, source_path, source_parts)?;
 
+        let file = FileContext {
+            source_path,
+            content: &content,
+            source_parts,
+            crate_name,
+            crate_path,
+        };
+
+        // ライブラリのルートはモジュールとして展開されるのでクレートのルートでのみ有効な属性を削除する
+        if self.is_library_root(source_parts) {
+            if let Some(replacement_spans) =
+                self.collected_modules.replacement_spans_mut(source_parts)
+            {
+                for attr in ast
+                    .attrs
+                    .iter()
+                    .filter(|attr| is_crate_level_attribute(attr))
+                {
+                    let span = attr.span();
+                    replacement_spans.push(ReplacementSpan::removal(span.start(), span.end()));
+                }
+            }
+        }
+
+        let names = bound_names(&ast.items);
         for item in &ast.items {
-            // トップレベルのuse文を解析
-            if let Item::Use(item_use) = item {
-                // use文から依存モジュールを取得
-                let module_items = collect_module_items(
-                    &item_use.tree,
-                    &self.package_name,
-                    &self.package_src_path,
-                    crate_name,
-                    crate_path,
-                    &self_path,
-                )?;
+            self.analyze_item(&file, item, &[], module_dir, &names)?;
+        }
 
-                for module_item in &module_items {
-                    // useの途中に現れるモジュールも含めて解決
-                    let (ModuleItemAccessibility::Indirect(module_item_path)
-                    | ModuleItemAccessibility::Direct(module_item_path)) = module_item;
-
-                    // モジュールのパス
-                    let (ModuleItemPath::File(parts, _)
-                    | ModuleItemPath::Dir(parts, _)
-                    | ModuleItemPath::Insoluble(parts)) = module_item_path;
-                    // モジュールパスの結合
-                    let full_parts = concat_module_parts(source_parts, parts, crate_name);
-                    // モジュールの参照先がライブラリクレートか
-                    let is_lib_crate = &full_parts == &[self.package_name.clone()];
-
-                    // ファイルが解決されるモジュールのみを登録
-                    if let ModuleItemPath::File(_, path) = module_item_path {
-                        // トップレベルのソースコードの解析時でありライブラリクレートが直接useされている場合に限り
-                        // モジュールの可視性をuseの指定に合わせる
-                        let module_vis = if is_lib_crate && source_parts.is_empty() {
-                            let module_vis = item_use.vis.to_token_stream().to_string();
-                            if module_vis.is_empty() {
-                                None
-                            } else {
-                                Some(module_vis)
+        Ok(())
+    }
+
+    /// アイテムを解析する
+    ///
+    /// # Arguments
+    ///
+    /// * `inline_parts` ファイル中でアイテムを囲むインラインモジュールのパス
+    ///
+    /// * `module_dir` mod宣言を解決するディレクトリ
+    ///
+    /// * `bound_names` アイテムを含むモジュールでuse文やアイテムにより定義されている名前
+    fn analyze_item(
+        &mut self,
+        file: &FileContext,
+        item: &Item,
+        inline_parts: &[String],
+        module_dir: &Path,
+        bound_names: &[String],
+    ) -> Result<(), ExpunchError> {
+        // テスト専用のアイテムや`cfg`の述語が偽となるアイテムは解析せずに削除する
+        let attrs = item_attributes(item);
+        let is_disabled = self.cfg.as_ref().is_some_and(|cfg| !cfg.is_enabled(attrs));
+        if (self.strip_tests && is_test_item(attrs)) || is_disabled {
+            if let Some(replacement_spans) = self
+                .collected_modules
+                .replacement_spans_mut(file.source_parts)
+            {
+                let span = item.span();
+                replacement_spans.push(ReplacementSpan::removal(span.start(), span.end()));
+            }
+            return Ok(());
+        }
+
+        // 評価済みの`cfg`属性を置き換える
+        // 注：use文とmod宣言は文全体の置換で属性を置き換える
+        let is_replaced = match item {
+            Item::Use(_) => true,
+            Item::Mod(item_mod) => item_mod.content.is_none(),
+            _ => false,
+        };
+        if !is_replaced {
+            self.resolve_cfg_attributes(file, attrs);
+        }
+
+        // 関数本体やimplなどの内部の`cfg`属性を評価する
+        // 注：インラインモジュールのアイテムはそれぞれモジュール直下のアイテムとして評価する
+        if let (Some(cfg), false) = (&self.cfg, matches!(item, Item::Use(_) | Item::Mod(_))) {
+            let resolved = NestedCfgResolver::resolve(cfg, item);
+            if let Some(replacement_spans) = self
+                .collected_modules
+                .replacement_spans_mut(file.source_parts)
+            {
+                replacement_spans.extend(resolved);
+            }
+        }
+
+        match item {
+            // モジュール直下のuse文を解析
+            Item::Use(item_use) => {
+                self.analyze_use_item(file, item_use, inline_parts, module_dir, true)?;
+            }
+            // モジュール直下のmod文を解析
+            Item::Mod(item_mod) => {
+                self.analyze_mod_item(file, item_mod, inline_parts, module_dir)?;
+            }
+            // 関数本体やimplなどの内部のuse文やパスを解析
+            _ => {
+                // ライブラリクレートのマクロ定義中の`$crate`を展開後のモジュールのパスに置き換える
+                if let Item::Macro(item_macro) = item {
+                    if self.is_library(file.crate_name) {
+                        self.analyze_macro_item(file, item_macro);
+                    }
+                }
+
+                if self.inline_includes {
+                    self.inline_include_macros(file, item)?;
+                }
+
+                let libraries = self.visible_libraries(file.crate_name);
+                for dependency in DependencyCollector::collect(item, &libraries, bound_names) {
+                    // `cfg`の評価により削除された位置の依存関係は解析しない
+                    let span = match &dependency.kind {
+                        DependencyKind::Use(item_use) => item_use.span(),
+                        DependencyKind::Path { segments, .. } => segments[0].span(),
+                    };
+                    if self
+                        .collected_modules
+                        .node(file.source_parts)
+                        .is_some_and(|node| node.is_removed(span.start(), span.end()))
+                    {
+                        continue;
+                    }
+
+                    let module_dir = dependency
+                        .inline_parts
+                        .iter()
+                        .fold(module_dir.to_path_buf(), |dir, part| dir.join(part));
+                    let inline_parts = [inline_parts, &dependency.inline_parts].concat();
+                    match dependency.kind {
+                        DependencyKind::Use(item_use) => {
+                            self.analyze_use_item(
+                                file,
+                                item_use,
+                                &inline_parts,
+                                &module_dir,
+                                false,
+                            )?;
+                        }
+                        DependencyKind::Path {
+                            segments,
+                            is_dollar_crate,
+                        } => {
+                            self.check_unresolved_path(file, &segments, &module_dir)?;
+                            // `$crate`はマクロ定義の解析時に置き換える
+                            if !is_dollar_crate {
+                                self.rewrite_path_root(file, &segments[0], &inline_parts);
                             }
-                        } else {
-                            Some(String::from("pub"))
-                        };
-
-                        // ソースコードが依存するモジュールを登録
-                        if let None = self.collected_modules.update(
-                            &full_parts,
-                            path.clone(),
-                            if let Some(vis) = &module_vis {
-                                Some(vis)
-                            } else {
-                                None
-                            },
-                        ) {
-                            // 依存するモジュールのソースコードを解析
-                            self.analyze_file_impl(
-                                path,
-                                &full_parts,
-                                // ライブラリクレートの場合はクレートを変更する
-                                &String::from(if is_lib_crate {
-                                    &self.package_name
-                                } else {
-                                    crate_name
-                                }),
-                                if is_lib_crate { path } else { crate_path },
+
+                            // パスの途中に現れるモジュールを取得
+                            let segments: Vec<String> =
+                                segments.iter().map(|segment| segment.to_string()).collect();
+                            let module_items = collect_path_module_items(
+                                &segments,
+                                &libraries,
+                                file.crate_path,
+                                &module_dir,
                             )?;
+                            let source_parts = [file.source_parts, &inline_parts].concat();
+                            self.register_module_items(file, &module_items, &source_parts, None)?;
                         }
                     }
                 }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 式や型、マクロに現れるパスの先頭を展開後のモジュールのパスに置き換える
+    ///
+    /// ライブラリクレート中の`crate`は`crate::<lib>`に、
+    /// トップレベルのソースコードのモジュール直下以外でのライブラリクレートの名前は`crate::<name>`に置き換える
+    fn rewrite_path_root(&mut self, file: &FileContext, first: &Ident, inline_parts: &[String]) {
+        let replacement = if first == "crate" {
+            if !self.is_library(file.crate_name) {
+                return;
+            }
+            format!("crate::{}", file.crate_name)
+        } else if file.source_parts.is_empty() && inline_parts.is_empty() {
+            return;
+        } else {
+            format!("crate::{}", first)
+        };
+
+        if let Some(replacement_spans) = self
+            .collected_modules
+            .replacement_spans_mut(file.source_parts)
+        {
+            let span = first.span();
+            if !replacement_spans
+                .iter()
+                .any(|replacement_span| replacement_span.start == span.start())
+            {
+                replacement_spans.push(ReplacementSpan::new(span.start(), span.end(), replacement));
+            }
+        }
+    }
+
+    /// 評価済みの`cfg`属性と`cfg_attr`属性を置き換える置換を追加する
+    fn resolve_cfg_attributes(&mut self, file: &FileContext, attrs: &[Attribute]) {
+        let cfg = match &self.cfg {
+            Some(cfg) => cfg,
+            None => return,
+        };
+        if let Some(replacement_spans) = self
+            .collected_modules
+            .replacement_spans_mut(file.source_parts)
+        {
+            for attr in attrs {
+                if let Some(resolved) = cfg.resolve_attribute(attr) {
+                    let span = attr.span();
+                    replacement_spans.push(ReplacementSpan::new(
+                        span.start(),
+                        span.end(),
+                        resolved.join(" "),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// ライブラリクレートのマクロ定義を解析する
+    ///
+    /// `$crate`を展開後のライブラリクレートのモジュールのパスに置き換え、
+    /// `#[macro_export]`されたマクロを記録する
+    fn analyze_macro_item(&mut self, file: &FileContext, item_macro: &ItemMacro) {
+        let name = match &item_macro.ident {
+            Some(ident) => ident.to_string(),
+            None => return,
+        };
+        if item_macro
+            .attrs
+            .iter()
+            .any(|attr| attr.path.is_ident("macro_export"))
+        {
+            let span = item_macro.span();
+            self.exported_macros.push(ExportedMacro {
+                library: String::from(file.crate_name),
+                module_parts: file.source_parts.to_vec(),
+                name,
+                start: span.start(),
+                end: span.end(),
+            });
+        }
+
+        // `$crate`の位置を収集
+        let mut occurrences: Vec<(LineColumn, LineColumn)> = Vec::new();
+        collect_dollar_crates(item_macro.mac.tokens.clone(), &mut occurrences);
+
+        if let Some(replacement_spans) = self
+            .collected_modules
+            .replacement_spans_mut(file.source_parts)
+        {
+            for (start, end) in occurrences {
+                replacement_spans.push(ReplacementSpan::new(
+                    start,
+                    end,
+                    format!("crate::{}", file.crate_name),
+                ));
+            }
+        }
+    }
+
+    /// `include!`、`include_str!`、`include_bytes!`の呼び出しをファイルの内容に置き換える
+    ///
+    /// パスは呼び出しを含む元のファイルを基準に解決する。
+    /// 注：取り込んだファイルの内容は解析しないので、その中のマクロやパスは置き換えない
+    fn inline_include_macros(
+        &mut self,
+        file: &FileContext,
+        item: &Item,
+    ) -> Result<(), ExpunchError> {
+        let base_dir = file.source_path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut invocations = Vec::new();
+        collect_item_include_macros(item, &mut invocations);
+
+        for (name, path, start, end, is_item) in invocations {
+            let include_path = base_dir.join(&path);
+            let content = read_include_file(&include_path, file.source_parts)?;
+            let replacement = if name == "include_bytes" {
+                make_byte_str_literal(&content)
+            } else {
+                let content =
+                    String::from_utf8(content).map_err(|source| ExpunchError::ReadFailed {
+                        path: include_path.clone(),
+                        module_parts: file.source_parts.to_vec(),
+                        source: io::Error::new(io::ErrorKind::InvalidData, source),
+                    })?;
+                if name == "include_str" {
+                    make_str_literal(&content)
+                } else if is_item || is_single_token_tree(content.trim_end()) {
+                    String::from(content.trim_end())
+                } else {
+                    // 式の位置では演算子の優先順位を保つため括弧で囲む
+                    format!("({})", content.trim_end())
+                }
+            };
+
+            if let Some(replacement_spans) = self
+                .collected_modules
+                .replacement_spans_mut(file.source_parts)
+            {
+                replacement_spans.push(ReplacementSpan::new(start, end, replacement));
+            }
+        }
+        Ok(())
+    }
+
+    /// use文を解析する
+    ///
+    /// # Arguments
+    ///
+    /// * `inline_parts` ファイル中でuse文を囲むインラインモジュールのパス
+    ///
+    /// * `module_dir` mod宣言を解決するディレクトリ
+    ///
+    /// * `is_module_item` use文がモジュール直下のアイテムであるか
+    fn analyze_use_item(
+        &mut self,
+        file: &FileContext,
+        item_use: &ItemUse,
+        inline_parts: &[String],
+        module_dir: &Path,
+        is_module_item: bool,
+    ) -> Result<(), ExpunchError> {
+        let crate_name = file.crate_name;
+        // インラインモジュール内のuse文はモジュールのパスとディレクトリを基準に解決する
+        let source_parts = &[file.source_parts, inline_parts].concat();
+        // ファイルのトップレベルのuse文か
+        let is_top_level = is_module_item && inline_parts.is_empty();
+
+        // ファイルに解決できないモジュールを警告
+        let mut paths = Vec::new();
+        collect_use_paths(&item_use.tree, &mut Vec::new(), &mut paths);
+        for path in &paths {
+            self.check_unresolved_path(file, path, module_dir)?;
+        }
+
+        // use文から依存モジュールを取得
+        let libraries = self.visible_libraries(crate_name);
+        let module_items =
+            collect_module_items(&item_use.tree, &libraries, file.crate_path, module_dir)?;
+
+        // トップレベルのソースコードの解析時でありライブラリクレートが直接useされている場合に限り
+        // モジュールの可視性をuseの指定に合わせる
+        let lib_vis = item_use.vis.to_token_stream().to_string();
+        self.register_module_items(
+            file,
+            &module_items,
+            source_parts,
+            if lib_vis.is_empty() {
+                None
+            } else {
+                Some(&lib_vis)
+            },
+        )?;
+
+        // `crate`の解決
+        let use_tree = self.resolve_modules(&item_use.tree, crate_name);
+
+        let use_tree = if file.source_parts.is_empty() {
+            if is_top_level {
+                // トップレベルのソースコードの解析時に限りトップレベルのモジュールのuseを削除する
+                self.remove_top_module(&use_tree)
+            } else if !inline_parts.is_empty() {
+                // インラインモジュール内では展開したモジュールをクレートルートから参照する
+                Some(self.prefix_crate_root(&use_tree))
+            } else {
+                Some(use_tree)
+            }
+        } else {
+            // 子モジュールのファイルでは展開したライブラリクレートをクレートルートから参照する
+            Some(self.prefix_crate_root(&use_tree))
+        };
+
+        // use文の評価済みの`cfg`属性を置き換える
+        // 注：入れ子になったuse文の属性もuse文全体の置換で置き換える
+        let mut resolved_attributes = Vec::new();
+        let mut attrs = Vec::new();
+        for attr in &item_use.attrs {
+            match &self.cfg {
+                Some(cfg) => match cfg.resolve_attribute(attr) {
+                    Some(resolved) => resolved_attributes.extend(resolved),
+                    None => attrs.push(attr.clone()),
+                },
+                None => attrs.push(attr.clone()),
+            }
+        }
+
+        let item_use_span = item_use.span();
+
+        // 入れ子になったuse文は変更がない場合は置換しない
+        if !is_top_level
+            && use_tree.as_ref() == Some(&item_use.tree)
+            && attrs.len() == item_use.attrs.len()
+        {
+            return Ok(());
+        }
+
+        let item_use = use_tree.map(|use_tree| ItemUse {
+            attrs,
+            vis: item_use.vis.clone(),
+            use_token: item_use.use_token,
+            leading_colon: item_use.leading_colon,
+            tree: use_tree,
+            semi_token: item_use.semi_token,
+        });
+        let span = item_use_span;
 
-                // `crate`の解決
-                let use_tree = self.resolve_modules(&item_use.tree, crate_name);
+        // トップレベルのソースコードのuse文はエクスポートされたマクロとの衝突を後で解消する
+        if is_top_level && file.source_parts.is_empty() {
+            if let Some(item_use) = &item_use {
+                self.root_uses.push(RootUse {
+                    start: span.start(),
+                    resolved_attributes: resolved_attributes.clone(),
+                    item_use: item_use.clone(),
+                });
+            }
+        }
+
+        // use文の削除置換の追加
+        if let Some(replacement_spans) = self
+            .collected_modules
+            .replacement_spans_mut(file.source_parts)
+        {
+            replacement_spans.push(ReplacementSpan::new(
+                span.start(),
+                span.end(),
+                make_use_replacement(&resolved_attributes, item_use.as_ref()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// 依存するモジュールのうちファイルが解決されるものを登録して解析する
+    ///
+    /// # Arguments
+    ///
+    /// * `source_parts` 依存元のモジュールのパス
+    ///
+    /// * `lib_vis` トップレベルのソースコードから参照されたライブラリクレートのモジュールの可視性
+    fn register_module_items(
+        &mut self,
+        file: &FileContext,
+        module_items: &[ModuleItemAccessibility],
+        source_parts: &[String],
+        lib_vis: Option<&str>,
+    ) -> Result<(), ExpunchError> {
+        for module_item in module_items {
+            // useの途中に現れるモジュールも含めて解決
+            let (ModuleItemAccessibility::Indirect(module_item_path)
+            | ModuleItemAccessibility::Direct(module_item_path)) = module_item;
+
+            // モジュールのパス
+            let (ModuleItemPath::File(parts, _)
+            | ModuleItemPath::Dir(parts, _)
+            | ModuleItemPath::Insoluble(parts)) = module_item_path;
+            // モジュールパスの結合
+            // 注：ライブラリクレートの名前から始まるパスはモジュールの位置に依らない
+            let full_parts = if parts.first().is_some_and(|first| self.is_library(first)) {
+                concat_module_parts(&[], parts, file.crate_name)
+            } else {
+                concat_module_parts(source_parts, parts, file.crate_name)
+            };
+            // モジュールの参照先がライブラリクレートか
+            let is_lib_crate = self.is_library_root(&full_parts);
 
-                let use_tree = if source_parts.is_empty() {
-                    // トップレベルのソースコードの解析時に限りトップレベルのモジュールのuseを削除する
-                    self.remove_top_module(&use_tree, crate_name)
+            // ファイルが解決されるモジュールのみを登録
+            if let ModuleItemPath::File(_, path) = module_item_path {
+                // トップレベルのソースコードの解析時でありライブラリクレートが参照されている場合に限り
+                // モジュールの可視性を指定に合わせる
+                let module_vis = if is_lib_crate && source_parts.is_empty() {
+                    lib_vis
                 } else {
-                    Some(use_tree)
+                    Some("pub")
                 };
 
-                // use文の削除置換の追加
-                if let Some(replacement_spans) =
-                    self.collected_modules.replacement_spans_mut(&source_parts)
+                // ソースコードが依存するモジュールを登録
+                if self
+                    .collected_modules
+                    .update(&full_parts, path.clone(), module_vis)
+                    .is_none()
                 {
-                    let span = item.span();
-                    replacement_spans.push(ReplacementSpan {
-                        start: span.start(),
-                        end: span.end(),
-                        replacement: if let Some(use_tree) = use_tree {
-                            Item::Use(ItemUse {
-                                attrs: item_use.attrs.clone(),
-                                vis: item_use.vis.clone(),
-                                use_token: item_use.use_token,
-                                leading_colon: item_use.leading_colon,
-                                tree: use_tree,
-                                semi_token: item_use.semi_token,
-                            })
-                            .to_token_stream()
-                            .to_string()
+                    // 依存するモジュールのソースコードを解析
+                    self.analyze_file_impl(
+                        path,
+                        &full_parts,
+                        // ライブラリクレートの場合はクレートを変更する
+                        if is_lib_crate {
+                            &full_parts[0]
                         } else {
-                            String::new()
+                            file.crate_name
                         },
-                    });
+                        if is_lib_crate { path } else { file.crate_path },
+                        &make_module_dir(path, is_lib_crate),
+                    )?;
                 }
             }
-            // トップレベルのmod文を解析
-            else if let Item::Mod(item_mod) = item {
-                // 宣言文の場合のみ処理
-                if let None = item_mod.content {
-                    // モジュールパスの結合
-                    let full_parts = concat_module_parts(
-                        source_parts,
-                        &vec![item_mod.ident.to_string()],
-                        crate_name,
-                    );
+        }
 
-                    // mod文から依存モジュールを取得
-                    let module_item_path = make_module_item_path(
-                        &full_parts,
-                        &self.package_name,
-                        &self.package_src_path,
-                        crate_path,
-                        &self_path,
-                    )?;
+        Ok(())
+    }
 
-                    // ファイルが解決されるモジュールのみを登録
-                    if let ModuleItemPath::File(_, path) = &module_item_path {
-                        // mod文の削除置換
-                        if let Some(replacement_spans) =
-                            self.collected_modules.replacement_spans_mut(&source_parts)
-                        {
-                            let span = item.span();
-                            replacement_spans.push(ReplacementSpan {
-                                start: span.start(),
-                                end: span.end(),
-                                replacement: String::new(),
-                            });
-                        }
+    /// mod文を解析する
+    ///
+    /// インラインモジュールの場合は内部のmod宣言を再帰的に解析する
+    ///
+    /// # Arguments
+    ///
+    /// * `inline_parts` ファイル中でmod文を囲むインラインモジュールのパス
+    ///
+    /// * `module_dir` mod宣言を解決するディレクトリ
+    fn analyze_mod_item(
+        &mut self,
+        file: &FileContext,
+        item_mod: &ItemMod,
+        inline_parts: &[String],
+        module_dir: &Path,
+    ) -> Result<(), ExpunchError> {
+        let name = item_mod.ident.to_string();
+        let path_attr = match &self.cfg {
+            Some(cfg) => cfg.path_attribute(&item_mod.attrs),
+            None => path_attribute(&item_mod.attrs),
+        };
+
+        if let Some((_, items)) = &item_mod.content {
+            // インラインモジュール内のmod宣言はモジュール名のディレクトリを基準に解決する
+            let inline_parts = [inline_parts, std::slice::from_ref(&name)].concat();
+            let module_dir = module_dir.join(path_attr.unwrap_or(name));
+            let names = bound_names(items);
+            for item in items {
+                self.analyze_item(file, item, &inline_parts, &module_dir, &names)?;
+            }
+            return Ok(());
+        }
+
+        // モジュールパスの結合
+        let relative_parts = [inline_parts, std::slice::from_ref(&name)].concat();
+        let full_parts = concat_module_parts(file.source_parts, &relative_parts, file.crate_name);
+
+        // mod文から依存モジュールを取得
+        // 注：インラインモジュール内の`#[path]`属性はmod宣言を解決するディレクトリを基準とする
+        let module_file = make_declared_module_path(
+            &name,
+            path_attr.as_deref(),
+            if inline_parts.is_empty() {
+                file.source_path.parent().unwrap_or_else(|| Path::new(""))
+            } else {
+                module_dir
+            },
+            module_dir,
+        );
+
+        // ファイルが解決されるモジュールのみを登録
+        if let Some((path, nested_module_dir)) = &module_file {
+            // mod文の削除置換
+            // インラインモジュール内の宣言は宣言の位置にモジュールを展開する
+            if let Some(replacement_spans) = self
+                .collected_modules
+                .replacement_spans_mut(file.source_parts)
+            {
+                let span = item_mod.span();
+                replacement_spans.push(ReplacementSpan {
+                    start: span.start(),
+                    end: span.end(),
+                    replacement: String::new(),
+                    expanded_module: if inline_parts.is_empty() {
+                        None
+                    } else {
+                        Some(relative_parts.clone())
+                    },
+                });
+            }
+
+            let module_vis = item_mod.vis.to_token_stream().to_string();
+
+            // ソースコードが依存するモジュールを登録
+            let is_new = self
+                .collected_modules
+                .update(
+                    &full_parts,
+                    path.clone(),
+                    if module_vis.is_empty() {
+                        None
+                    } else {
+                        Some(&module_vis)
+                    },
+                )
+                .is_none();
+
+            // インラインモジュール内の宣言は末尾に展開しない
+            if !inline_parts.is_empty() {
+                for depth in 1..=relative_parts.len() {
+                    let parts = [file.source_parts, &relative_parts[..depth]].concat();
+                    if let Some(node) = self.collected_modules.node_mut(&parts) {
+                        node.embedded = true;
+                    }
+                }
+            }
+
+            if is_new {
+                // `#[path]`属性を除いた属性を展開後のモジュールに引き継ぐ
+                let attributes = match &self.cfg {
+                    Some(cfg) => cfg.inherited_attributes(&item_mod.attrs),
+                    None => item_mod
+                        .attrs
+                        .iter()
+                        .filter(|attr| !attr.path.is_ident("path"))
+                        .map(|attr| attr.to_token_stream().to_string())
+                        .collect(),
+                };
+                if let Some(node) = self.collected_modules.node_mut(&full_parts) {
+                    node.attributes = attributes;
+                }
+
+                // 新たに登録できた場合にのみ依存するモジュールのソースコードを解析
+                // 注：mod宣言ではクレートは変更されない
+                self.analyze_file_impl(
+                    path,
+                    &full_parts,
+                    file.crate_name,
+                    file.crate_path,
+                    nested_module_dir,
+                )?;
+            }
+        } else {
+            // 宣言されたモジュールのファイルが見つからない
+            let span = item_mod.ident.span();
+            let module_path = full_parts.join("::");
+            self.push_diagnostic(Diagnostic::new(
+                format!("file not found for module `{}`", module_path),
+                format!("モジュール `{}` のファイルが見つかりません", module_path),
+                file.source_path,
+                file.content,
+                span.start(),
+                span.end(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// パッケージから始まるパスのうちファイルに解決できないモジュールを警告する
+    ///
+    /// モジュールのファイルに定義されていない名前が現れた位置を報告する
+    ///
+    /// # Arguments
+    ///
+    /// * `segments` パスのセグメント
+    ///
+    /// * `self_path` `self`や`super`を解決するディレクトリ
+    fn check_unresolved_path(
+        &mut self,
+        file: &FileContext,
+        segments: &[Ident],
+        self_path: &Path,
+    ) -> Result<(), ExpunchError> {
+        // 参照できるライブラリクレートから始まるパスのみを対象とする
+        let libraries = self.visible_libraries(file.crate_name);
+        let is_rooted = match segments.first() {
+            Some(first) => {
+                libraries.iter().any(|library| first == &library.name)
+                    || (*first == "crate" && self.is_library(file.crate_name))
+            }
+            None => false,
+        };
+        if !is_rooted {
+            return Ok(());
+        }
 
-                        let module_vis = item_mod.vis.to_token_stream().to_string();
+        let parts: Vec<String> = segments.iter().map(|segment| segment.to_string()).collect();
+        // 直前のセグメントのモジュールのファイル
+        let mut parent_file = None;
+        for length in 1..=parts.len() {
+            let name = &parts[length - 1];
+            if name == "crate" {
+                parent_file = Some(file.crate_path.clone());
+                continue;
+            }
+            if name == "self" {
+                continue;
+            }
 
-                        // ソースコードが依存するモジュールを登録
-                        if let None = self.collected_modules.update(
-                            &full_parts,
-                            path.clone(),
-                            if module_vis.is_empty() {
-                                None
+            match make_module_item_path(&parts[..length], &libraries, file.crate_path, self_path)? {
+                ModuleItemPath::File(_, path) => parent_file = Some(path),
+                ModuleItemPath::Dir(_, _) => parent_file = None,
+                ModuleItemPath::Insoluble(_) => {
+                    // モジュール以外のアイテムとして定義されている名前は警告しない
+                    let is_defined = match &parent_file {
+                        Some(path) => self.is_defined_in(path, &parts[..length - 1], name)?,
+                        None => false,
+                    };
+                    if !is_defined {
+                        let span = segments[length - 1].span();
+                        let module_path = parts[..length].join("::");
+                        let diagnostic = Diagnostic::new(
+                            format!("unresolved module or item `{}`", module_path),
+                            format!("`{}` をファイルまたはアイテムに解決できません", module_path),
+                            file.source_path,
+                            file.content,
+                            span.start(),
+                            span.end(),
+                        );
+                        if length == 2 {
+                            let library = if parts[0] == "crate" {
+                                String::from(file.crate_name)
                             } else {
-                                Some(&module_vis)
-                            },
-                        ) {
-                            // 新たに登録できた場合にのみ依存するモジュールのソースコードを解析
-                            // 注：mod宣言ではクレートは変更されない
-                            self.analyze_file_impl(path, &full_parts, crate_name, crate_path)?;
+                                parts[0].clone()
+                            };
+                            // エクスポートされたマクロはクレートのルートに置かれるので解析後に再度判定する
+                            self.unresolved_root_items.push((
+                                library,
+                                name.clone(),
+                                diagnostic.clone(),
+                            ));
                         }
+                        self.push_diagnostic(diagnostic);
                     }
+                    break;
                 }
             }
         }
@@ -275,118 +1149,281 @@ impl Expuncher {
         Ok(())
     }
 
+    /// モジュールのファイルで名前が定義されているか
+    ///
+    /// グロブのuse文などで定義される名前を特定できない場合は定義されているとみなす
+    fn is_defined_in(
+        &mut self,
+        path: &Path,
+        module_parts: &[String],
+        name: &str,
+    ) -> Result<bool, ExpunchError> {
+        if !self.defined_names.contains_key(path) {
+            let ast = parse_source_file(path, module_parts)?;
+            self.defined_names
+                .insert(path.to_path_buf(), defined_names(&ast.items));
+        }
+        Ok(match &self.defined_names[path] {
+            Some(names) => names.iter().any(|defined| defined == name),
+            None => true,
+        })
+    }
+
+    /// 同じ位置の警告が登録されていなければ登録する
+    fn push_diagnostic(&mut self, diagnostic: Diagnostic) {
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    /// 解析中に検出した警告を取得する
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
     /// 解析した内容に基づいてソースコードを標準出力に出力する
-    pub fn dump(&self) -> Result<(), String> {
-        self.dump_module(&self.collected_modules, &Vec::new(), "crate")
+    pub fn dump(&self) -> Result<(), ExpunchError> {
+        let stdout = io::stdout();
+        let mut writer = stdout.lock();
+        self.dump_to(&mut writer)
+    }
+
+    /// 解析した内容に基づいてソースコードを`writer`に出力する
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` 展開したソースコードの出力先
+    pub fn dump_to<W: Write>(&self, writer: &mut W) -> Result<(), ExpunchError> {
+        self.dump_with_source_map(writer).map(|_| ())
+    }
+
+    /// 解析した内容に基づいてソースコードを`writer`に出力し、元のファイルの行との対応を取得する
+    ///
+    /// prettypleaseで整形する場合は行の対応が失われるので空のソースマップを返す
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` 展開したソースコードの出力先
+    pub fn dump_with_source_map<W: Write>(
+        &self,
+        writer: &mut W,
+    ) -> Result<SourceMap, ExpunchError> {
+        if self.formatting != Formatting::Pretty && self.line_ending.is_none() {
+            let mut mapped_writer = MappedWriter::new(&mut *writer, 1);
+            self.dump_module(
+                &mut mapped_writer,
+                &self.collected_modules,
+                &[],
+                "crate",
+                "\n",
+            )?;
+            let (_, mappings) = mapped_writer.into_parts();
+            writer.flush().map_err(write_error)?;
+            return Ok(SourceMap::new(mappings));
+        }
+
+        // 出力全体を加工する場合は一度文字列として展開する
+        let mut mapped_writer = MappedWriter::new(Vec::new(), 1);
+        self.dump_module(
+            &mut mapped_writer,
+            &self.collected_modules,
+            &[],
+            "crate",
+            "\n",
+        )?;
+        let (buffer, mut mappings) = mapped_writer.into_parts();
+        let mut content =
+            String::from_utf8(buffer).map_err(|source| ExpunchError::InvalidUtf8 { source })?;
+        if self.formatting == Formatting::Pretty {
+            // 注：prettypleaseはドキュメンテーションコメント以外のコメントを保持しない
+            let ast = syn::parse_file(&content)
+                .map_err(|source| ExpunchError::FormatFailed { source })?;
+            content = prettyplease::unparse(&ast);
+            mappings.clear();
+        }
+        if let Some(line_ending) = self.line_ending {
+            content = line_ending.normalize(&content);
+        }
+        write!(writer, "{}", content).map_err(write_error)?;
+        writer.flush().map_err(write_error)?;
+        Ok(SourceMap::new(mappings))
+    }
+
+    /// 解析した内容に基づいて展開したソースコードを文字列として取得する
+    pub fn to_string(&self) -> Result<String, ExpunchError> {
+        let mut buffer = Vec::new();
+        self.dump_to(&mut buffer)?;
+        String::from_utf8(buffer).map_err(|source| ExpunchError::InvalidUtf8 { source })
     }
 
-    fn dump_module(
+    fn dump_module<W: Write>(
         &self,
+        writer: &mut MappedWriter<W>,
         module: &ModuleNode,
         source_parts: &[String],
         crate_name: &str,
-    ) -> Result<(), String> {
+        line_ending: &str,
+    ) -> Result<(), ExpunchError> {
+        // 生成する行は元のファイルと同じ改行文字で出力する
+        let mut line_ending = line_ending;
         if let Some(source_path) = &module.path {
-            let file = File::open(source_path).or_else(|_| {
-                Err(format!(
-                    "File {0} not exists
-                    ファイル {1} が存在しません",
-                    source_path.to_str().unwrap_or("(undisplayable path)"),
-                    source_path.to_str().unwrap_or("（表示できないパス）"),
-                )
-                .to_owned())
-            })?;
-
-            // 既に置換の配列はソート済みとする
-            let mut replacement_spans_iter = module.replacement_spans.iter();
-            let mut replacement_span_or_none = replacement_spans_iter.next();
-
-            // 注：LineColumn::columnはUTF-8文字としてのカウントである
-            for (line_number, line) in BufReader::new(file).lines().enumerate() {
-                let line_number = line_number + 1; // 1-indexed
-
-                if let io::Result::Ok(line) = line {
-                    if let Some(replacement_span) = replacement_span_or_none {
-                        if line_number < replacement_span.start.line {
-                            // 置換開始行以前はそのままの一行を出力
-                            print!("{}", line);
-                        } else if line_number == replacement_span.start.line {
-                            // 置換開始行は置換開始列以前の文字列を出力
-                            let pre_line: String =
-                                line.chars().take(replacement_span.start.column).collect();
-                            print!("{}", pre_line);
-                            // 置換文字列を出力
-                            print!("{}", replacement_span.replacement);
-                        }
+            let content = module.read_source(source_path, source_parts)?;
+            line_ending = detect_line_ending(&content).unwrap_or(line_ending);
 
-                        if line_number == replacement_span.end.line {
-                            // 置換終了行は置換終了列以降の文字列を出力
-                            let post_line: String =
-                                line.chars().skip(replacement_span.end.column).collect();
-                            print!("{}", post_line);
+            // 置換の範囲を一度だけバイト単位の位置に変換して順に適用する
+            // 既に置換の配列はソート済みで互いに重ならないことを検証済みとする
+            let line_index = LineIndex::new(&content);
+            let mut position = 0;
+            for replacement_span in &module.replacement_spans {
+                let start = line_index.offset(replacement_span.start).max(position);
+                let end = line_index.offset(replacement_span.end).max(start);
 
-                            // 次の置換に遷移
-                            replacement_span_or_none = replacement_spans_iter.next();
-                        }
-                    } else {
-                        // 置換が存在しない場合はそのまま出力
-                        print!("{}", line);
-                    }
+                // 置換開始位置までの元の内容を出力
+                writer
+                    .write_source(
+                        &content[position..start],
+                        source_path,
+                        source_parts,
+                        line_index.line(position),
+                    )
+                    .map_err(write_error)?;
+                // 置換文字列を出力
+                write!(writer, "{}", replacement_span.replacement).map_err(write_error)?;
 
-                    // 改行
-                    println!();
+                // 宣言の位置に展開するモジュールを出力
+                if let Some(expanded_parts) = &replacement_span.expanded_module {
+                    if let (Some((name, parent_parts)), Some(child)) =
+                        (expanded_parts.split_last(), module.node(expanded_parts))
+                    {
+                        let parent_parts = [source_parts, parent_parts].concat();
+                        write!(writer, "{}", line_ending).map_err(write_error)?;
+                        self.dump_child(
+                            writer,
+                            name,
+                            child,
+                            &parent_parts,
+                            crate_name,
+                            line_ending,
+                        )?;
+                    }
                 }
+
+                position = end;
             }
+            // 残りの内容を改行文字も含めてそのまま出力
+            writer
+                .write_source(
+                    &content[position..],
+                    source_path,
+                    source_parts,
+                    line_index.line(position),
+                )
+                .map_err(write_error)?;
+
+            // 末尾に改行のないファイルは後続の出力やモジュールの閉じ括弧の前に改行する
+            let has_following = !module.appended_items.is_empty()
+                || module.children.iter().any(|(_, child)| !child.embedded);
+            if !content.is_empty()
+                && !content.ends_with('\n')
+                && (!source_parts.is_empty() || has_following)
+            {
+                write!(writer, "{}", line_ending).map_err(write_error)?;
+            }
+        }
+
+        for appended_item in &module.appended_items {
+            write!(writer, "{}{}", appended_item, line_ending).map_err(write_error)?;
         }
 
         // 依存するソースコードを展開
-        for (name, child) in &module.children {
-            // モジュールパスの結合
-            let full_parts = concat_module_parts(source_parts, &vec![name.clone()], crate_name);
-            // モジュールの参照先がライブラリクレートか
-            let is_lib_crate = &full_parts == &[self.package_name.clone()];
+        let mut children: Vec<_> = module.children.iter().collect();
+        if self.module_order == ModuleOrder::Alphabetical {
+            children.sort_by(|(name, _), (other_name, _)| name.cmp(other_name));
+        }
+        for (name, child) in children {
+            // インラインモジュール中の宣言の位置に展開済みのモジュールは除外
+            if child.embedded {
+                continue;
+            }
 
-            println!();
-            println!(
-                "{}mod {} {{",
-                if let Some(visibility) = &child.visibility {
-                    visibility.clone() + " "
-                } else {
-                    String::from("")
-                },
-                name
-            );
+            write!(writer, "{}", line_ending).map_err(write_error)?;
+            self.dump_child(writer, name, child, source_parts, crate_name, line_ending)?;
+            write!(writer, "{}", line_ending).map_err(write_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// 子モジュールをmodブロックとして出力する
+    fn dump_child<W: Write>(
+        &self,
+        writer: &mut MappedWriter<W>,
+        name: &str,
+        child: &ModuleNode,
+        source_parts: &[String],
+        crate_name: &str,
+        line_ending: &str,
+    ) -> Result<(), ExpunchError> {
+        // モジュールパスの結合
+        let full_parts = concat_module_parts(source_parts, &[name.to_owned()], crate_name);
+        // モジュールの参照先がライブラリクレートか
+        let is_lib_crate = self.is_library_root(&full_parts);
+
+        for attribute in &child.attributes {
+            write!(writer, "{}{}", attribute, line_ending).map_err(write_error)?;
+        }
+        write!(
+            writer,
+            "{}mod {} {{{}",
+            if let Some(visibility) = &child.visibility {
+                visibility.clone() + " "
+            } else {
+                String::from("")
+            },
+            name,
+            line_ending
+        )
+        .map_err(write_error)?;
+        // ライブラリクレートの場合はクレートを変更する
+        let child_crate_name = if is_lib_crate {
+            &full_parts[0]
+        } else {
+            crate_name
+        };
+        if self.formatting == Formatting::Indent {
+            // 子孫のモジュールも含めて出力した内容を一段字下げする
+            // 注：字下げでは行数が変化しないので行の対応はそのまま引き継ぐ
+            let mut buffer = MappedWriter::new(Vec::new(), writer.line());
             self.dump_module(
+                &mut buffer,
                 child,
                 &full_parts,
-                // ライブラリクレートの場合はクレートを変更する
-                &String::from(if is_lib_crate {
-                    &self.package_name
-                } else {
-                    crate_name
-                }),
+                child_crate_name,
+                line_ending,
             )?;
-            println!("}}");
+            let (buffer, mappings) = buffer.into_parts();
+            let content =
+                String::from_utf8(buffer).map_err(|source| ExpunchError::InvalidUtf8 { source })?;
+            write!(writer, "{}", indent_lines(&content)).map_err(write_error)?;
+            for mapping in mappings {
+                writer.push_mapping(mapping);
+            }
+        } else {
+            self.dump_module(writer, child, &full_parts, child_crate_name, line_ending)?;
         }
+        write!(writer, "}}").map_err(write_error)?;
 
         Ok(())
     }
 
-    pub fn remove_top_module(&self, use_tree: &UseTree, crate_name: &str) -> Option<UseTree> {
-        self.remove_top_module_impl(use_tree, crate_name, 0)
+    pub fn remove_top_module(&self, use_tree: &UseTree) -> Option<UseTree> {
+        self.remove_top_module_impl(use_tree, 0)
     }
 
-    fn remove_top_module_impl(
-        &self,
-        use_tree: &UseTree,
-        crate_name: &str,
-        depth: usize,
-    ) -> Option<UseTree> {
+    fn remove_top_module_impl(&self, use_tree: &UseTree, depth: usize) -> Option<UseTree> {
         match use_tree {
             UseTree::Path(use_path) => {
-                if let Some(new_tree) =
-                    self.remove_top_module_impl(&use_path.tree, crate_name, depth + 1)
-                {
+                if let Some(new_tree) = self.remove_top_module_impl(&use_path.tree, depth + 1) {
                     Some(UseTree::Path(UsePath {
                         ident: use_path.ident.clone(),
                         colon2_token: use_path.colon2_token,
@@ -404,13 +1441,11 @@ impl Expuncher {
             }
             // 展開対象のトップレベルのクレートをuse文から削除する
             UseTree::Name(use_name) => {
-                if depth == 0
-                    && (use_name.ident.to_string() == "crate"
-                        || use_name.ident.to_string() == self.package_name)
+                if (depth == 0
+                    && (use_name.ident == "crate" || self.is_library(&use_name.ident.to_string())))
+                    || (depth == 1 && use_name.ident == "self")
                 {
                     None
-                } else if depth == 1 && use_name.ident.to_string() == "self" {
-                    None
                 } else {
                     Some(use_tree.clone())
                 }
@@ -421,7 +1456,7 @@ impl Expuncher {
                 items: use_group
                     .items
                     .iter()
-                    .filter_map(|item| self.remove_top_module_impl(item, crate_name, depth))
+                    .filter_map(|item| self.remove_top_module_impl(item, depth))
                     .collect(),
             })),
             UseTree::Rename(_) => Some(use_tree.clone()),
@@ -429,25 +1464,113 @@ impl Expuncher {
         }
     }
 
+    /// ライブラリクレートから直接インポートされたエクスポート済みのマクロをuseツリーから削除する
+    ///
+    /// # Arguments
+    ///
+    /// * `names` エクスポートされたマクロのライブラリクレートと名前の組
+    pub fn remove_exported_macros(
+        &self,
+        use_tree: &UseTree,
+        names: &[(String, String)],
+    ) -> Option<UseTree> {
+        match use_tree {
+            UseTree::Path(use_path) if self.is_library(&use_path.ident.to_string()) => {
+                let library = use_path.ident.to_string();
+                let names: Vec<String> = names
+                    .iter()
+                    .filter(|(exported_library, _)| exported_library == &library)
+                    .map(|(_, name)| name.clone())
+                    .collect();
+                let tree = match use_path.tree.as_ref() {
+                    UseTree::Name(use_name) if names.contains(&use_name.ident.to_string()) => {
+                        return None;
+                    }
+                    // 空のグループは許容されているのでそのままグループとして返す
+                    UseTree::Group(use_group) => UseTree::Group(UseGroup {
+                        brace_token: use_group.brace_token,
+                        items: use_group
+                            .items
+                            .iter()
+                            .filter(|item| {
+                                !matches!(item, UseTree::Name(use_name)
+                                    if names.contains(&use_name.ident.to_string()))
+                            })
+                            .cloned()
+                            .collect(),
+                    }),
+                    tree => tree.clone(),
+                };
+                Some(UseTree::Path(UsePath {
+                    ident: use_path.ident.clone(),
+                    colon2_token: use_path.colon2_token,
+                    tree: Box::new(tree),
+                }))
+            }
+            UseTree::Group(use_group) => Some(UseTree::Group(UseGroup {
+                brace_token: use_group.brace_token,
+                items: use_group
+                    .items
+                    .iter()
+                    .filter_map(|item| self.remove_exported_macros(item, names))
+                    .collect(),
+            })),
+            _ => Some(use_tree.clone()),
+        }
+    }
+
+    /// ライブラリクレートから始まるuse文をクレートルートからのパスに変換する
+    pub fn prefix_crate_root(&self, use_tree: &UseTree) -> UseTree {
+        match use_tree {
+            UseTree::Path(UsePath { ident, .. })
+            | UseTree::Name(UseName { ident })
+            | UseTree::Rename(UseRename { ident, .. })
+                if self.is_library(&ident.to_string()) =>
+            {
+                UseTree::Path(UsePath {
+                    ident: Ident::new("crate", ident.span()),
+                    colon2_token: Default::default(),
+                    tree: Box::new(use_tree.clone()),
+                })
+            }
+            UseTree::Group(use_group) => UseTree::Group(UseGroup {
+                brace_token: use_group.brace_token,
+                items: use_group
+                    .items
+                    .iter()
+                    .map(|item| self.prefix_crate_root(item))
+                    .collect(),
+            }),
+            _ => use_tree.clone(),
+        }
+    }
+
     pub fn resolve_modules(&self, use_tree: &UseTree, crate_name: &str) -> UseTree {
         self.resolve_modules_impl(use_tree, crate_name)
     }
 
     fn resolve_modules_impl(&self, use_tree: &UseTree, crate_name: &str) -> UseTree {
         match use_tree {
+            // 展開したライブラリクレートはクレートルート直下のモジュールとして参照する
+            UseTree::Path(use_path) if use_path.ident == "crate" && crate_name != "crate" => {
+                UseTree::Path(UsePath {
+                    ident: use_path.ident.clone(),
+                    colon2_token: use_path.colon2_token,
+                    tree: Box::new(UseTree::Path(UsePath {
+                        ident: Ident::new(crate_name, use_path.ident.span()),
+                        colon2_token: use_path.colon2_token,
+                        tree: Box::new(self.resolve_modules_impl(&use_path.tree, crate_name)),
+                    })),
+                })
+            }
             UseTree::Path(use_path) => UseTree::Path(UsePath {
-                ident: match use_path.ident {
-                    _ if use_path.ident.to_string() == "crate" => {
-                        Ident::new(crate_name, use_path.ident.span())
-                    }
-                    _ => use_path.ident.clone(),
-                },
+                ident: use_path.ident.clone(),
                 colon2_token: use_path.colon2_token,
                 tree: Box::new(self.resolve_modules_impl(&use_path.tree, crate_name)),
             }),
             // 展開対象のトップレベルのクレートをuse文から削除する
             UseTree::Name(use_name) => {
-                if use_name.ident.to_string() == "crate" {
+                if use_name.ident == "crate" {
                     UseTree::Name(UseName {
                         ident: Ident::new(crate_name, use_name.ident.span()),
                     })
@@ -469,28 +1592,353 @@ impl Expuncher {
     }
 }
 
+/// useツリーの末端までのパスを収集する
+///
+/// グロブや末尾の`self`はその直前までのパスとする
+fn collect_use_paths(use_tree: &UseTree, prefix: &mut Vec<Ident>, paths: &mut Vec<Vec<Ident>>) {
+    match use_tree {
+        UseTree::Path(use_path) => {
+            prefix.push(use_path.ident.clone());
+            collect_use_paths(&use_path.tree, prefix, paths);
+            prefix.pop();
+        }
+        UseTree::Name(UseName { ident }) | UseTree::Rename(UseRename { ident, .. }) => {
+            if ident == "self" {
+                paths.push(prefix.clone());
+            } else {
+                paths.push([prefix.as_slice(), std::slice::from_ref(ident)].concat());
+            }
+        }
+        UseTree::Glob(_) => paths.push(prefix.clone()),
+        UseTree::Group(use_group) => {
+            for item in &use_group.items {
+                collect_use_paths(item, prefix, paths);
+            }
+        }
+    }
+}
+
+/// 評価済みの属性とuse文から置換文字列を作成する
+///
+/// use文が`None`の場合は削除する
+fn make_use_replacement(resolved_attributes: &[String], item_use: Option<&ItemUse>) -> String {
+    match item_use {
+        Some(item_use) => {
+            let mut replacement = resolved_attributes.to_vec();
+            replacement.push(item_use.to_token_stream().to_string());
+            replacement.join(" ")
+        }
+        None => String::new(),
+    }
+}
+
+/// 行の内容と末尾の改行文字（`\r\n`、`\n`または空）に分割する
+fn split_line_ending(line: &str) -> (&str, &str) {
+    if let Some(stripped) = line.strip_suffix("\r\n") {
+        (stripped, "\r\n")
+    } else if let Some(stripped) = line.strip_suffix('\n') {
+        (stripped, "\n")
+    } else {
+        (line, "")
+    }
+}
+
+/// 最初の行の改行文字（`\r\n`または`\n`）を検出する
+///
+/// 改行を含まない場合は`None`を返す
+fn detect_line_ending(content: &str) -> Option<&'static str> {
+    let index = content.find('\n')?;
+    if content[..index].ends_with('\r') {
+        Some("\r\n")
+    } else {
+        Some("\n")
+    }
+}
+
+/// 空行と複数行にわたるリテラルの2行目以降を除いて各行を一段字下げする
+fn indent_lines(content: &str) -> String {
+    // 字下げによってリテラルの内容が変化しないようにする
+    let mut literal_lines = HashSet::new();
+    if let Ok(tokens) = content.parse::<TokenStream>() {
+        collect_literal_lines(tokens, &mut literal_lines);
+    }
+
+    let mut indented = String::with_capacity(content.len());
+    for (line_number, line) in content.split_inclusive('\n').enumerate() {
+        if !line.trim().is_empty() && !literal_lines.contains(&(line_number + 1)) {
+            indented.push_str("    ");
+        }
+        indented.push_str(line);
+    }
+    indented
+}
+
+/// 複数行にわたるリテラルの2行目以降の行番号を収集する
+fn collect_literal_lines(tokens: TokenStream, lines: &mut HashSet<usize>) {
+    for token in tokens {
+        match token {
+            TokenTree::Literal(literal) => {
+                let span = literal.span();
+                lines.extend(span.start().line + 1..=span.end().line);
+            }
+            TokenTree::Group(group) => collect_literal_lines(group.stream(), lines),
+            _ => {}
+        }
+    }
+}
+
+/// マクロ定義のトークン列から`$crate`の開始位置と終了位置を収集する
+fn collect_dollar_crates(tokens: TokenStream, occurrences: &mut Vec<(LineColumn, LineColumn)>) {
+    let mut dollar = None;
+    for token in tokens {
+        match token {
+            TokenTree::Punct(punct) if punct.as_char() == '$' => {
+                dollar = Some(punct.span().start());
+                continue;
+            }
+            TokenTree::Ident(ident) if ident == "crate" => {
+                if let Some(start) = dollar {
+                    occurrences.push((start, ident.span().end()));
+                }
+            }
+            TokenTree::Group(group) => collect_dollar_crates(group.stream(), occurrences),
+            _ => {}
+        }
+        dollar = None;
+    }
+}
+
+/// `include!`などの呼び出しのマクロ名、パス、開始位置、終了位置とアイテムの位置にあるか
+type IncludeInvocation = (String, String, LineColumn, LineColumn, bool);
+
+/// アイテムに含まれる`include!`、`include_str!`、`include_bytes!`の呼び出しを収集する
+///
+/// モジュール直下の`include!(...);`はアイテムとして末尾のセミコロンも含めて収集する
+fn collect_item_include_macros(item: &Item, invocations: &mut Vec<IncludeInvocation>) {
+    match item {
+        Item::Macro(ItemMacro {
+            ident: None, mac, ..
+        }) if mac.path.is_ident("include") => {
+            let span = item.span();
+            if let Ok(path) = syn::parse2::<LitStr>(mac.tokens.clone()) {
+                invocations.push((
+                    String::from("include"),
+                    path.value(),
+                    span.start(),
+                    span.end(),
+                    true,
+                ));
+            }
+        }
+        Item::Mod(ItemMod {
+            content: Some((_, items)),
+            ..
+        }) => {
+            for item in items {
+                collect_item_include_macros(item, invocations);
+            }
+        }
+        _ => collect_include_macros(item.to_token_stream(), invocations),
+    }
+}
+
+/// トークン列から`include!`、`include_str!`、`include_bytes!`の呼び出しを収集する
+///
+/// 引数が文字列リテラルである呼び出しのマクロ名、パス、開始位置と終了位置を収集する
+fn collect_include_macros(tokens: TokenStream, invocations: &mut Vec<IncludeInvocation>) {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    for (index, token) in tokens.iter().enumerate() {
+        match (token, tokens.get(index + 1), tokens.get(index + 2)) {
+            (
+                TokenTree::Ident(ident),
+                Some(TokenTree::Punct(punct)),
+                Some(TokenTree::Group(group)),
+            ) if punct.as_char() == '!'
+                && (ident == "include" || ident == "include_str" || ident == "include_bytes") =>
+            {
+                if let Ok(path) = syn::parse2::<LitStr>(group.stream()) {
+                    invocations.push((
+                        ident.to_string(),
+                        path.value(),
+                        ident.span().start(),
+                        group.span().end(),
+                        false,
+                    ));
+                }
+            }
+            (TokenTree::Group(group), _, _) => collect_include_macros(group.stream(), invocations),
+            _ => {}
+        }
+    }
+}
+
+/// ソースコードがリテラルや括弧で囲まれた部分などの単一のトークンからなるか
+fn is_single_token_tree(content: &str) -> bool {
+    content
+        .parse::<TokenStream>()
+        .is_ok_and(|tokens| tokens.into_iter().count() == 1)
+}
+
+/// 文字列の内容を表す文字列リテラルを作成する
+///
+/// 改行文字を含めて内容を保つため、`\r`を含む場合はエスケープし、それ以外は生文字列リテラルとする
+fn make_str_literal(content: &str) -> String {
+    if content.contains('\r') {
+        return format!("{:?}", content);
+    }
+    // 内容に現れない数の`#`で囲む
+    let mut hashes = String::new();
+    while content.contains(&format!("\"{}", hashes)) {
+        hashes.push('#');
+    }
+    format!("r{0}\"{1}\"{0}", hashes, content)
+}
+
+/// バイト列の内容を表すバイト文字列リテラルを作成する
+fn make_byte_str_literal(content: &[u8]) -> String {
+    let escaped: String = content
+        .iter()
+        .flat_map(|&byte| std::ascii::escape_default(byte))
+        .map(char::from)
+        .collect();
+    format!("b\"{}\"", escaped)
+}
+
+/// 出力の書き込みに失敗した際のエラーを作成する
+fn write_error(source: io::Error) -> ExpunchError {
+    ExpunchError::WriteFailed { source }
+}
+
 #[derive(Debug)]
 pub struct ModuleNode {
     pub path: Option<PathBuf>,
+    /// ファイルの代わりに用いるソースコードの内容（標準入力から読み込んだ場合など）
+    pub content: Option<String>,
     pub visibility: Option<String>,
+    /// 展開後のモジュールに付与する属性
+    pub attributes: Vec<String>,
+    /// 末尾ではなくインラインモジュール中の宣言の位置に展開するか
+    pub embedded: bool,
+    /// ファイルの内容の後に追加するアイテム
+    pub appended_items: Vec<String>,
     pub replacement_spans: Vec<ReplacementSpan>,
-    pub children: HashMap<String, ModuleNode>,
+    /// 子モジュールの名前とノードの組（最初に登録された順）
+    pub children: Vec<(String, ModuleNode)>,
 }
 
+/// ファイルの内容に対する編集
+///
+/// 開始位置から終了位置までを置換文字列に置き換える。開始位置と終了位置が等しい場合は挿入となる
 #[derive(Debug)]
 pub struct ReplacementSpan {
     pub start: LineColumn,
     pub end: LineColumn,
     pub replacement: String,
+    /// 置換文字列の後に展開する子孫モジュールの相対パス
+    pub expanded_module: Option<Vec<String>>,
+}
+
+impl ReplacementSpan {
+    /// 範囲を置換文字列に置き換える編集を作成する
+    pub fn new(start: LineColumn, end: LineColumn, replacement: String) -> ReplacementSpan {
+        ReplacementSpan {
+            start,
+            end,
+            replacement,
+            expanded_module: None,
+        }
+    }
+
+    /// 範囲を削除する編集を作成する
+    pub fn removal(start: LineColumn, end: LineColumn) -> ReplacementSpan {
+        ReplacementSpan::new(start, end, String::new())
+    }
+
+    /// 位置に文字列を挿入する編集を作成する
+    pub fn insertion(position: LineColumn, text: String) -> ReplacementSpan {
+        ReplacementSpan::new(position, position, text)
+    }
+}
+
+/// ソースコードの各行の開始位置を保持し、`LineColumn`をバイト単位の位置に変換する
+struct LineIndex<'a> {
+    content: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(content: &'a str) -> LineIndex<'a> {
+        let line_starts = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        LineIndex {
+            content,
+            line_starts,
+        }
+    }
+
+    /// バイト単位の位置を含む行の行番号（1始まり）を取得する
+    fn line(&self, offset: usize) -> usize {
+        self.line_starts
+            .partition_point(|&line_start| line_start <= offset)
+    }
+
+    /// 位置がファイルの範囲内にあるか
+    fn contains(&self, position: LineColumn) -> bool {
+        match self.line_starts.get(position.line.wrapping_sub(1)) {
+            Some(&line_start) => {
+                let line_end = self
+                    .line_starts
+                    .get(position.line)
+                    .copied()
+                    .unwrap_or(self.content.len());
+                let (line, _) = split_line_ending(&self.content[line_start..line_end]);
+                position.column <= line.chars().count()
+            }
+            None => false,
+        }
+    }
+
+    /// 位置をバイト単位の位置に変換する
+    ///
+    /// 行末を超える列は改行文字の直前に、末尾を超える行はファイルの末尾に丸める。
+    /// 注：LineColumn::lineは1始まり、LineColumn::columnはUTF-8文字としてのカウントである
+    fn offset(&self, position: LineColumn) -> usize {
+        let line_start = match self.line_starts.get(position.line.saturating_sub(1)) {
+            Some(&line_start) => line_start,
+            None => return self.content.len(),
+        };
+        let line_end = self
+            .line_starts
+            .get(position.line)
+            .copied()
+            .unwrap_or(self.content.len());
+        let (line, _) = split_line_ending(&self.content[line_start..line_end]);
+        line_start
+            + line
+                .char_indices()
+                .nth(position.column)
+                .map_or(line.len(), |(index, _)| index)
+    }
+}
+
+impl Default for ModuleNode {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ModuleNode {
     pub fn new() -> ModuleNode {
         ModuleNode {
             path: None,
+            content: None,
             visibility: Some(String::from("pub")),
+            attributes: Vec::new(),
+            embedded: false,
+            appended_items: Vec::new(),
             replacement_spans: Vec::new(),
-            children: HashMap::new(),
+            children: Vec::new(),
         }
     }
 
@@ -508,30 +1956,84 @@ impl ModuleNode {
                 Some(_) => Some(source_path),
                 None => {
                     self.path = Some(source_path);
-                    self.visibility = if let Some(visibility) = visibility {
-                        Some(String::from(visibility))
-                    } else {
-                        None
-                    };
+                    self.visibility = visibility.map(String::from);
                     None
                 }
             }
         } else {
-            let child = self
+            let index = match self
                 .children
-                .entry(module_parts[0].clone())
-                .or_insert(ModuleNode::new());
+                .iter()
+                .position(|(name, _)| name == &module_parts[0])
+            {
+                Some(index) => index,
+                None => {
+                    self.children
+                        .push((module_parts[0].clone(), ModuleNode::new()));
+                    self.children.len() - 1
+                }
+            };
+            let (_, child) = &mut self.children[index];
 
             child.update(&module_parts[1..], source_path, visibility)
         }
     }
 
+    /// モジュールのソースコードの内容を取得する
+    ///
+    /// 内容が登録されていない場合はファイルから読み込む
+    pub fn read_source(
+        &self,
+        source_path: &Path,
+        source_parts: &[String],
+    ) -> Result<String, ExpunchError> {
+        match &self.content {
+            Some(content) => Ok(content.clone()),
+            None => read_source_file(source_path, source_parts),
+        }
+    }
+
+    /// 名前から子モジュールのノードを取得する
+    pub fn child(&self, name: &str) -> Option<&ModuleNode> {
+        self.children
+            .iter()
+            .find(|(child_name, _)| child_name == name)
+            .map(|(_, child)| child)
+    }
+
+    /// 名前から変更可能な子モジュールのノードを取得する
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut ModuleNode> {
+        self.children
+            .iter_mut()
+            .find(|(child_name, _)| child_name == name)
+            .map(|(_, child)| child)
+    }
+
+    /// モジュールパスから子孫モジュールのノードを取得する
+    pub fn node(&self, module_parts: &[String]) -> Option<&ModuleNode> {
+        if module_parts.is_empty() {
+            Some(self)
+        } else {
+            self.child(&module_parts[0])?.node(&module_parts[1..])
+        }
+    }
+
+    /// モジュールパスから変更可能な子孫モジュールのノードを取得する
+    pub fn node_mut(&mut self, module_parts: &[String]) -> Option<&mut ModuleNode> {
+        if module_parts.is_empty() {
+            Some(self)
+        } else {
+            self.child_mut(&module_parts[0])?
+                .node_mut(&module_parts[1..])
+        }
+    }
+
     /// 置換用のスパンの配列を取得する
     pub fn replacement_spans(&self, module_parts: &[String]) -> Option<&[ReplacementSpan]> {
         if module_parts.is_empty() {
             Some(&self.replacement_spans)
         } else {
-            if let Some(child) = self.children.get(&module_parts[0]) {
+            if let Some(child) = self.child(&module_parts[0]) {
                 child.replacement_spans(&module_parts[1..])
             } else {
                 None
@@ -547,7 +2049,7 @@ impl ModuleNode {
         if module_parts.is_empty() {
             Some(&mut self.replacement_spans)
         } else {
-            if let Some(child) = self.children.get_mut(&module_parts[0]) {
+            if let Some(child) = self.child_mut(&module_parts[0]) {
                 child.replacement_spans_mut(&module_parts[1..])
             } else {
                 None
@@ -555,9 +2057,96 @@ impl ModuleNode {
         }
     }
 
+    /// 範囲が削除の置換に含まれているか
+    pub fn is_removed(&self, start: LineColumn, end: LineColumn) -> bool {
+        self.replacement_spans.iter().any(|span| {
+            span.replacement.is_empty()
+                && span.expanded_module.is_none()
+                && span.start <= start
+                && end <= span.end
+        })
+    }
+
+    /// 出力の前にすべてのノードの置換をソートして検証する
+    ///
+    /// 削除される範囲の内部の置換は取り除き、
+    /// 置換がファイルの範囲外にある場合や互いに重なる場合はエラーとする
+    ///
+    /// # Arguments
+    ///
+    /// * `module_parts` このノードのモジュールのパス
+    pub fn finalize_replacement_spans(
+        &mut self,
+        module_parts: &mut Vec<String>,
+    ) -> Result<(), ExpunchError> {
+        self.remove_inner_replacement_spans();
+        self.sort_replacement_spans();
+
+        if let (Some(path), false) = (&self.path, self.replacement_spans.is_empty()) {
+            let content = self.read_source(path, module_parts)?;
+            let line_index = LineIndex::new(&content);
+            let mut previous: Option<&ReplacementSpan> = None;
+            for span in &self.replacement_spans {
+                if span.end < span.start
+                    || !line_index.contains(span.start)
+                    || !line_index.contains(span.end)
+                {
+                    return Err(ExpunchError::ReplacementOutOfRange {
+                        path: path.clone(),
+                        module_parts: module_parts.clone(),
+                        start: span.start,
+                        end: span.end,
+                    });
+                }
+                // 同じ位置への挿入は許容する
+                if let Some(previous) = previous.filter(|previous| span.start < previous.end) {
+                    return Err(ExpunchError::OverlappingReplacements {
+                        path: path.clone(),
+                        module_parts: module_parts.clone(),
+                        first: (previous.start, previous.end),
+                        second: (span.start, span.end),
+                    });
+                }
+                previous = Some(span);
+            }
+        }
+
+        for (name, child) in &mut self.children {
+            module_parts.push(name.clone());
+            child.finalize_replacement_spans(module_parts)?;
+            module_parts.pop();
+        }
+        Ok(())
+    }
+
+    /// 削除される範囲の内部にある置換を取り除く
+    ///
+    /// 削除される範囲の両端への挿入は残す
+    fn remove_inner_replacement_spans(&mut self) {
+        let removals: Vec<(LineColumn, LineColumn)> = self
+            .replacement_spans
+            .iter()
+            .filter(|span| {
+                span.replacement.is_empty()
+                    && span.expanded_module.is_none()
+                    && span.start < span.end
+            })
+            .map(|span| (span.start, span.end))
+            .collect();
+        self.replacement_spans.retain(|span| {
+            !removals.iter().any(|&(start, end)| {
+                start <= span.start
+                    && span.end <= end
+                    && (start, end) != (span.start, span.end)
+                    && !(span.start == span.end && (span.start == start || span.end == end))
+            })
+        });
+    }
+
     /// 置換用のスパンの配列を行数列数の早い順にソートする
+    ///
+    /// 開始位置が等しい置換は追加された順を保つ
     pub fn sort_replacement_spans(&mut self) {
-        self.replacement_spans
-            .sort_unstable_by_key(|span| span.start);
+        self.replacement_spans.sort_by_key(|span| span.start);
     }
 }
@@ -1,2 +1,8 @@
+pub mod cfg;
+pub mod dependency;
+pub mod diagnostic;
+pub mod error;
 pub mod expuncher;
 pub mod module;
+pub mod shaker;
+pub mod source_map;
@@ -1,6 +1,13 @@
-use cargo_expunch::expuncher::Expuncher;
-use cargo_metadata::MetadataCommand;
+use cargo_expunch::cfg::CfgSet;
+use cargo_expunch::diagnostic::relative_path;
+use cargo_expunch::error::ExpunchError;
+use cargo_expunch::expuncher::{Expuncher, Formatting, LineEnding, ModuleOrder};
+use cargo_expunch::module::crate_ident;
+use cargo_expunch::source_map::SourceMap;
+use cargo_metadata::{DependencyKind, Metadata, MetadataCommand, NodeDep, Package};
 use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
 use std::path::PathBuf;
 
 fn help() {
@@ -10,77 +17,593 @@ Expand `use`d modules or declared `mod`ule in a Rust source-code into that conte
 Rustソースコード中のuse文とモジュール宣言をワークスペースのライブラリクレートに含まれるソースコードの内容に展開する
 
 USAGE:
-    cargo expunch <source_code_path>
-
-    * Use this subcommand at the directory of your workspace
-      このサブコマンドはワークスペースのディレクトリで使用する必要があります
+    cargo expunch [OPTIONS] <source_code_path>
+    cargo expunch [OPTIONS] [-] < <source_code_path>
+    cargo expunch [OPTIONS] --bin <name>
+    cargo expunch [OPTIONS] --example <name>
+    cargo expunch locate <line> --source-map <file>
 
 OPTIONS:
-    h, --help               Prints help information
-                            ヘルプを表示する
+    -h, --help                      Prints help information
+                                    ヘルプを表示する
+
+    -o, --output <file>             Writes the expanded source-code to the file instead of the standard output
+                                    展開したソースコードを標準出力の代わりにファイルへ書き込む
+
+        --source-map <file>         Writes the JSON source map from the lines of the expanded source-code to the original files
+                                    展開したソースコードの行から元のファイルへのJSON形式のソースマップを書き込む
+
+        --manifest-path <path>      Path to the Cargo.toml of the workspace
+                                    ワークスペースのCargo.tomlへのパス
+
+    -p, --package <name>            Package whose library crate is expanded
+                                    展開するライブラリクレートのパッケージ
+
+        --bin <name>                Expands the source-code of the binary target instead of the path
+                                    パスの代わりにバイナリターゲットのソースコードを展開する
+
+        --example <name>            Expands the source-code of the example target instead of the path
+                                    パスの代わりにexampleターゲットのソースコードを展開する
+
+        --stdin-path <path>         Path used to resolve `mod` declarations of the source-code read from the standard input
+                                    標準入力から読み込んだソースコードのmod宣言の解決に用いるパス
+
+        --module-order <order>      Order of the expanded sibling modules: `declaration` (default) or `alphabetical`
+                                    展開する兄弟モジュールの順序: `declaration`（既定）または `alphabetical`
+
+        --format                    Formats the expanded source-code with `prettyplease` (comments other than doc comments are removed)
+                                    展開したソースコードを `prettyplease` で整形する（ドキュメンテーションコメント以外のコメントは取り除かれる）
+
+        --indent                    Indents the contents of the expanded modules according to their depth
+                                    展開したモジュールの内容を入れ子の深さに合わせて字下げする
+
+        --line-ending <style>       Normalizes the line endings of the expanded source-code: `lf`, `crlf` or `native`
+                                    展開したソースコードの改行文字を統一する: `lf`、`crlf` または `native`
+
+        --no-tree-shaking           Keeps the items of the library crate that are not referenced
+                                    参照されないライブラリクレートのアイテムを取り除かずに残す
+
+        --keep-tests                Keeps the items with `#[cfg(test)]` or `#[test]`
+                                    `#[cfg(test)]` や `#[test]` が付与されたアイテムを取り除かずに残す
+
+        --inline-includes           Replaces `include!`, `include_str!` and `include_bytes!` with the contents of the files
+                                    `include!`、`include_str!`、`include_bytes!` をファイルの内容に置き換える
+
+        --cfg <spec>                Enables the configuration `name` or `name=\"value\"` to evaluate `cfg` attributes
+                                    `cfg` 属性の評価に用いる設定 `name` または `name=\"value\"` を有効にする
+
+        --features <features>       Enables the comma or space separated features to evaluate `cfg` attributes
+                                    `cfg` 属性の評価に用いるカンマまたは空白区切りのフィーチャーを有効にする
+
+        --include-dep <name>        Also expands the library crate of the registry or vendored dependency
+                                    レジストリまたはベンダリングされた依存クレートのライブラリクレートも展開する
+
+        --offline                   Runs `cargo metadata` without accessing the network
+                                    ネットワークにアクセスせずに `cargo metadata` を実行する
 
 ARGS:
-    source_code_path        Path to a Rust source code
-                            Rustソースコードへのパス
+    source_code_path        Path to a Rust source code, or `-` to read it from the standard input
+                            Rustソースコードへのパス。`-` の場合は標準入力から読み込む
+
+SUBCOMMANDS:
+    locate <line>           Prints the original `file:line` of the line of the expanded source-code using `--source-map`
+                            `--source-map` を用いて展開したソースコードの行に対応する元の `file:line` を表示する
 "
     );
 }
 
+/// 展開するソースコードの指定
+#[derive(Debug)]
+enum Source {
+    /// ソースコードへのパス
+    Path(PathBuf),
+    /// Cargoのターゲットの種類と名前
+    Target { kind: &'static str, name: String },
+    /// 標準入力
+    Stdin,
+}
+
+/// コマンドライン引数で指定された設定
+#[derive(Debug, Default)]
+struct Options {
+    output: Option<PathBuf>,
+    source_map: Option<PathBuf>,
+    manifest_path: Option<PathBuf>,
+    stdin_path: Option<PathBuf>,
+    package: Option<String>,
+    module_order: ModuleOrder,
+    formatting: Formatting,
+    line_ending: Option<LineEnding>,
+    no_tree_shaking: bool,
+    keep_tests: bool,
+    inline_includes: bool,
+    cfg: Vec<String>,
+    features: Vec<String>,
+    include_deps: Vec<String>,
+    offline: bool,
+}
+
+/// コマンドライン引数の解析結果
+enum Command {
+    Help,
+    Expunch(Source, Box<Options>),
+    /// 展開したソースコードの行とソースマップ
+    Locate(usize, PathBuf),
+}
+
 fn main() {
-    let mut args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
 
-    // サブコマンドの場合は第2引数に"expunch"がくるので除外する
-    if let Some(arg) = args.get(1) {
+    // サブコマンドの場合は第1引数に"expunch"がくるので除外する
+    if let Some(arg) = args.first() {
         if arg == "expunch" {
-            args.remove(1);
+            args.remove(0);
         }
     }
 
-    match args.len() {
-        // コマンドライン引数が指定されていない
-        1 => {
-            eprintln!(
-                "Specify the path to a Rust source-code in the option `source_code_path`
-引数 source_code_path にRustソースコードへのパスを指定してください"
-            )
-        }
+    let command = match args.first() {
+        Some(arg) if arg == "locate" => parse_locate_args(&args[1..]),
+        _ => parse_args(&args),
+    };
+    match command {
         // ヘルプ表示の指定
-        2 if &args[1] == "-h" || &args[1] == "--help" => {
+        Ok(Command::Help) => {
             // ヘルプを表示
             help();
         }
-        // コマンドライン引数を必要数指定した
-        2 => {
-            // 引数
-            let source_code_path = &args[1];
-
+        Ok(Command::Expunch(source, options)) => {
             // 実行
-            if let Err(message) = expunch_file(source_code_path) {
-                eprintln!("{}", message);
+            if let Err(error) = expunch_file(&source, &options) {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            };
+        }
+        Ok(Command::Locate(line, source_map)) => {
+            if let Err(error) = locate(line, &source_map) {
+                eprintln!("{}", error);
+                std::process::exit(1);
             };
         }
-        // 必要外の指定
-        _ => {
+        // 不正な指定
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!();
             // ヘルプを表示
             help();
+            std::process::exit(2);
+        }
+    }
+}
+
+/// コマンドライン引数を解析する
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut source = None;
+    let mut options = Options::default();
+    let mut args_iter = args.iter();
+
+    while let Some(arg) = args_iter.next() {
+        // `--name=value`形式の指定を分割
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_owned())),
+            _ => (arg.as_str(), None),
+        };
+        // オプションの値を取得
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args_iter.next().cloned())
+                .ok_or_else(|| {
+                    format!(
+                        "Specify a value for the option `{0}`
+オプション `{0}` に値を指定してください",
+                        name
+                    )
+                })
+        };
+
+        match name {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => options.output = Some(PathBuf::from(value()?)),
+            "--source-map" => options.source_map = Some(PathBuf::from(value()?)),
+            "--manifest-path" => options.manifest_path = Some(PathBuf::from(value()?)),
+            "--stdin-path" => options.stdin_path = Some(PathBuf::from(value()?)),
+            "-p" | "--package" => options.package = Some(value()?),
+            "--module-order" => {
+                options.module_order = match value()?.as_str() {
+                    "declaration" => ModuleOrder::Declaration,
+                    "alphabetical" => ModuleOrder::Alphabetical,
+                    order => {
+                        return Err(format!(
+                            "Unknown module order `{0}`; specify `declaration` or `alphabetical`
+不明なモジュールの順序 `{0}` が指定されました。`declaration` または `alphabetical` を指定してください",
+                            order
+                        ));
+                    }
+                }
+            }
+            "--format" => options.formatting = Formatting::Pretty,
+            "--indent" => options.formatting = Formatting::Indent,
+            "--line-ending" => {
+                options.line_ending = Some(match value()?.as_str() {
+                    "lf" => LineEnding::Lf,
+                    "crlf" => LineEnding::Crlf,
+                    "native" => LineEnding::Native,
+                    line_ending => {
+                        return Err(format!(
+                            "Unknown line ending `{0}`; specify `lf`, `crlf` or `native`
+不明な改行文字 `{0}` が指定されました。`lf`、`crlf` または `native` を指定してください",
+                            line_ending
+                        ));
+                    }
+                })
+            }
+            "--no-tree-shaking" => options.no_tree_shaking = true,
+            "--keep-tests" => options.keep_tests = true,
+            "--inline-includes" => options.inline_includes = true,
+            "--cfg" => options.cfg.push(value()?),
+            "--features" => options.features.push(value()?),
+            "--include-dep" => options.include_deps.push(value()?),
+            "--offline" => options.offline = true,
+            "--bin" => {
+                let target = Source::Target {
+                    kind: "bin",
+                    name: value()?,
+                };
+                set_source(&mut source, target)?;
+            }
+            "--example" => {
+                let target = Source::Target {
+                    kind: "example",
+                    name: value()?,
+                };
+                set_source(&mut source, target)?;
+            }
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(format!(
+                    "Unknown option `{0}`
+不明なオプション `{0}` が指定されました",
+                    name
+                ));
+            }
+            "-" => set_source(&mut source, Source::Stdin)?,
+            _ => set_source(&mut source, Source::Path(PathBuf::from(arg)))?,
+        }
+    }
+
+    // パスが指定されず標準入力がパイプされている場合は標準入力から読み込む
+    if source.is_none() && !io::stdin().is_terminal() {
+        source = Some(Source::Stdin);
+    }
+    // 整形すると行の対応が失われる
+    if options.source_map.is_some() && options.formatting == Formatting::Pretty {
+        return Err(String::from(
+            "`--source-map` cannot be used with `--format`
+`--source-map` は `--format` と同時に指定できません",
+        ));
+    }
+    let source = source.ok_or_else(|| {
+        String::from(
+            "Specify the path to a Rust source-code in the option `source_code_path`, or `--bin` or `--example`
+引数 source_code_path にRustソースコードへのパスを指定するか `--bin` または `--example` を指定してください",
+        )
+    })?;
+
+    Ok(Command::Expunch(source, Box::new(options)))
+}
+
+/// `locate`サブコマンドの引数を解析する
+fn parse_locate_args(args: &[String]) -> Result<Command, String> {
+    let mut line = None;
+    let mut source_map = None;
+    let mut args_iter = args.iter();
+
+    while let Some(arg) = args_iter.next() {
+        match arg.split_once('=') {
+            Some(("--source-map", value)) => source_map = Some(PathBuf::from(value)),
+            _ if arg == "--source-map" => {
+                source_map = args_iter.next().map(PathBuf::from);
+            }
+            _ if arg == "-h" || arg == "--help" => return Ok(Command::Help),
+            _ => {
+                line = Some(arg.parse::<usize>().map_err(|_| {
+                    format!(
+                        "Specify a line number instead of `{0}`
+`{0}` の代わりに行番号を指定してください",
+                        arg
+                    )
+                })?)
+            }
+        }
+    }
+
+    match (line, source_map) {
+        (Some(line), Some(source_map)) => Ok(Command::Locate(line, source_map)),
+        _ => Err(String::from(
+            "Specify a line number and `--source-map <file>`
+行番号と `--source-map <file>` を指定してください",
+        )),
+    }
+}
+
+/// 展開するソースコードの指定が重複していなければ設定する
+fn set_source(source: &mut Option<Source>, new_source: Source) -> Result<(), String> {
+    if source.is_some() {
+        return Err(String::from(
+            "Specify only one of `source_code_path`, `--bin` and `--example`
+`source_code_path`、`--bin`、`--example` はいずれか一つのみ指定してください",
+        ));
+    }
+    *source = Some(new_source);
+    Ok(())
+}
+
+/// 展開対象のパッケージを取得する
+fn select_package<'a>(
+    metadata: &'a Metadata,
+    package_name: Option<&str>,
+) -> Result<&'a Package, ExpunchError> {
+    match package_name {
+        Some(package_name) => metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_members.contains(&package.id))
+            .find(|package| package.name == package_name),
+        None => metadata.root_package(),
+    }
+    .ok_or_else(|| ExpunchError::PackageNotFound {
+        name: package_name.map(String::from),
+    })
+}
+
+/// パッケージが推移的に依存するローカル（パス指定またはワークスペースのメンバー）のライブラリクレートを取得する
+///
+/// レジストリやベンダリングされたクレートは`include_deps`で名前が指定されたもののみを対象とする。
+/// ソースコード中で参照される名前とパッケージの組を依存関係の探索順に返す
+fn local_dependencies<'a>(
+    metadata: &'a Metadata,
+    package: &Package,
+    include_deps: &[String],
+) -> Result<Vec<(String, &'a Package)>, ExpunchError> {
+    let resolve = match &metadata.resolve {
+        Some(resolve) => resolve,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut dependencies: Vec<(String, &Package)> = Vec::new();
+    let mut queue = vec![package.id.clone()];
+    while let Some(id) = queue.pop() {
+        let node = match resolve.nodes.iter().find(|node| node.id == id) {
+            Some(node) => node,
+            None => continue,
+        };
+        for dep in &node.deps {
+            if is_build_dependency(dep) || dep.pkg == package.id {
+                continue;
+            }
+            let dependency = match metadata.packages.iter().find(|p| p.id == dep.pkg) {
+                Some(dependency) => dependency,
+                None => continue,
+            };
+            // レジストリやGitのクレートは指定された場合に限り対象とする
+            let is_included = include_deps
+                .iter()
+                .any(|name| name == &dep.name || name == &dependency.name);
+            if (dependency.source.is_some() && !is_included)
+                || dependencies
+                    .iter()
+                    .any(|(_, registered)| registered.id == dependency.id)
+            {
+                continue;
+            }
+            dependencies.push((dep.name.clone(), dependency));
+            queue.push(dependency.id.clone());
         }
     }
+
+    // 指定された依存クレートが見つからない
+    if let Some(name) = include_deps.iter().find(|name| {
+        !dependencies
+            .iter()
+            .any(|(dep_name, dependency)| dep_name == *name || &dependency.name == *name)
+    }) {
+        return Err(ExpunchError::DependencyNotFound {
+            name: name.clone(),
+            package: package.name.clone(),
+        });
+    }
+
+    Ok(dependencies)
+}
+
+/// ビルドスクリプトのみの依存関係か
+///
+/// ビルドスクリプトの依存関係はソースコードから参照されない
+fn is_build_dependency(dep: &NodeDep) -> bool {
+    !dep.dep_kinds.is_empty()
+        && dep
+            .dep_kinds
+            .iter()
+            .all(|info| info.kind == DependencyKind::Build)
+}
+
+/// パッケージが直接依存するクレートをソースコード中で参照する名前を取得する
+fn direct_dependency_names(metadata: &Metadata, package: &Package) -> Vec<String> {
+    metadata
+        .resolve
+        .iter()
+        .flat_map(|resolve| resolve.nodes.iter())
+        .filter(|node| node.id == package.id)
+        .flat_map(|node| node.deps.iter())
+        .filter(|dep| !is_build_dependency(dep))
+        .map(|dep| dep.name.clone())
+        .collect()
+}
+
+/// パッケージのライブラリクレートの名前とルートのファイルへのパスを取得する
+///
+/// ライブラリターゲットが存在しない場合は既定の`src/lib.rs`とパッケージ名を用いる。
+/// 注：クレート名はハイフンをアンダースコアに置き換えたものとなる
+fn library_crate(package: &Package) -> (String, PathBuf) {
+    match package
+        .targets
+        .iter()
+        .find(|target| target.kind.iter().any(|kind| kind.ends_with("lib")))
+    {
+        Some(target) => (
+            crate_ident(&target.name),
+            target.src_path.as_std_path().to_path_buf(),
+        ),
+        None => (
+            crate_ident(&package.name),
+            package
+                .manifest_path
+                .parent()
+                .map(|path| path.as_std_path().to_path_buf())
+                .unwrap_or_default()
+                .join("src")
+                .join("lib.rs"),
+        ),
+    }
+}
+
+/// 展開するソースコードへのパスを取得する
+///
+/// ターゲットが指定された場合は`cargo metadata`のターゲット一覧から`src_path`を探す
+fn resolve_source_path(
+    package: &Package,
+    source: &Source,
+    options: &Options,
+) -> Result<PathBuf, ExpunchError> {
+    match source {
+        Source::Path(path) => Ok(path.clone()),
+        // 標準入力の場合はmod宣言を指定されたパスかカレントディレクトリを基準に解決する
+        Source::Stdin => Ok(options
+            .stdin_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("<stdin>"))),
+        Source::Target { kind, name } => package
+            .targets
+            .iter()
+            .find(|target| &target.name == name && target.kind.iter().any(|k| k == kind))
+            .map(|target| target.src_path.as_std_path().to_path_buf())
+            .ok_or_else(|| ExpunchError::TargetNotFound {
+                kind: kind.to_string(),
+                name: name.clone(),
+                package: package.name.clone(),
+            }),
+    }
 }
 
 /// Rustソースコードを解析して展開する
-fn expunch_file(source_code_path: &str) -> Result<(), String> {
-    let source_code_path = PathBuf::from(source_code_path);
-    let package_path = PathBuf::from(".");
-    let metadata = MetadataCommand::new()
-        .manifest_path("./Cargo.toml")
-        .current_dir(&package_path)
+fn expunch_file(source: &Source, options: &Options) -> Result<(), ExpunchError> {
+    let mut metadata_command = MetadataCommand::new();
+    if let Some(manifest_path) = &options.manifest_path {
+        metadata_command.manifest_path(manifest_path);
+    }
+    if options.offline {
+        metadata_command.other_options(vec![String::from("--offline")]);
+    }
+    let metadata = metadata_command
         .exec()
-        .unwrap();
-    let package = metadata.root_package().unwrap();
+        .map_err(|source| ExpunchError::MetadataFailed { source })?;
+    let package = select_package(&metadata, options.package.as_deref())?;
 
-    let mut expuncher = Expuncher::new(&package.name, package_path.join("src"));
-    expuncher.analyze_source_file(&source_code_path)?;
-    expuncher.dump()?;
+    let (crate_name, lib_path) = library_crate(package);
+    let mut expuncher = Expuncher::new(&crate_name, lib_path);
+    expuncher.set_library_dependencies(&crate_name, &direct_dependency_names(&metadata, package));
+    for (name, dependency) in local_dependencies(&metadata, package, &options.include_deps)? {
+        let (_, lib_path) = library_crate(dependency);
+        expuncher.add_library(&name, lib_path);
+        expuncher.set_library_dependencies(&name, &direct_dependency_names(&metadata, dependency));
+    }
+    expuncher.set_module_order(options.module_order);
+    expuncher.set_formatting(options.formatting);
+    if let Some(line_ending) = options.line_ending {
+        expuncher.set_line_ending(line_ending);
+    }
+    expuncher.set_tree_shaking(!options.no_tree_shaking);
+    expuncher.set_strip_tests(!options.keep_tests);
+    expuncher.set_inline_includes(options.inline_includes);
+    // 設定が指定された場合に限り`cfg`属性を評価する
+    if !options.cfg.is_empty() || !options.features.is_empty() {
+        let mut cfg = CfgSet::new();
+        for spec in &options.cfg {
+            cfg.insert_spec(spec);
+        }
+        for features in &options.features {
+            cfg.insert_features(features);
+        }
+        // テスト専用のアイテムを残す場合は`test`を有効とする
+        if options.keep_tests {
+            cfg.insert("test", None);
+        }
+        expuncher.set_cfg(cfg);
+    }
+    let source_path = resolve_source_path(package, source, options)?;
+    if let Source::Stdin = source {
+        let mut content = String::new();
+        io::stdin()
+            .read_to_string(&mut content)
+            .map_err(|source| ExpunchError::StdinReadFailed { source })?;
+        expuncher.analyze_source_code(&source_path, content)?;
+    } else {
+        expuncher.analyze_source_file(&source_path)?;
+    }
+
+    // 解決できなかったモジュールを警告
+    for diagnostic in expuncher.diagnostics() {
+        eprintln!("{}", diagnostic);
+        eprintln!();
+    }
+
+    let source_map = if let Some(output) = &options.output {
+        let file = File::create(output).map_err(|source| ExpunchError::CreateFailed {
+            path: output.clone(),
+            source,
+        })?;
+        expuncher.dump_with_source_map(&mut BufWriter::new(file))?
+    } else {
+        let stdout = io::stdout();
+        expuncher.dump_with_source_map(&mut stdout.lock())?
+    };
+
+    if let Some(source_map_path) = &options.source_map {
+        let file = File::create(source_map_path).map_err(|source| ExpunchError::CreateFailed {
+            path: source_map_path.clone(),
+            source,
+        })?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &source_map).map_err(|source| {
+            ExpunchError::WriteFailed {
+                source: source.into(),
+            }
+        })?;
+        // 破棄時の書き込みのエラーは無視されるので明示的に書き出す
+        writer
+            .flush()
+            .map_err(|source| ExpunchError::WriteFailed { source })?;
+    }
+
+    Ok(())
+}
+
+/// ソースマップを用いて展開したソースコードの行に対応する元のファイルの行を表示する
+fn locate(line: usize, source_map_path: &PathBuf) -> Result<(), ExpunchError> {
+    let content = std::fs::read_to_string(source_map_path).map_err(|source| {
+        ExpunchError::SourceMapReadFailed {
+            path: source_map_path.clone(),
+            source,
+        }
+    })?;
+    let source_map: SourceMap =
+        serde_json::from_str(&content).map_err(|source| ExpunchError::InvalidSourceMap {
+            path: source_map_path.clone(),
+            source,
+        })?;
 
+    let (path, source_line) = source_map
+        .locate(line)
+        .ok_or(ExpunchError::LineNotMapped { line })?;
+    println!("{}:{}", relative_path(path).display(), source_line);
     Ok(())
 }
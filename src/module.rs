@@ -1,12 +1,28 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use syn::{self, UseTree};
+use crate::error::ExpunchError;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use syn::{self, Attribute, Item, Lit, Meta, NestedMeta, UseTree};
+
+/// パッケージ名やターゲット名からソースコード中で参照されるクレート名を取得する
+///
+/// `my-lib`のようなハイフンを含む名前は`my_lib`として参照される
+pub fn crate_ident(name: &str) -> String {
+    name.replace('-', "_")
+}
 
 pub fn concat_module_parts(prefix: &[String], suffix: &[String], crate_name: &str) -> Vec<String> {
     let mut full_parts = prefix.to_vec();
     for part in suffix {
         match part {
-            _ if part == "crate" || part == crate_name => {
+            // `crate`はクレートのルートモジュールのパスに置き換える
+            _ if part == "crate" => {
+                full_parts.clear();
+                if crate_name != "crate" {
+                    full_parts.push(String::from(crate_name));
+                }
+            }
+            _ if part == crate_name => {
                 full_parts.clear();
                 full_parts.push(part.clone());
             }
@@ -22,6 +38,25 @@ pub fn concat_module_parts(prefix: &[String], suffix: &[String], crate_name: &st
     full_parts
 }
 
+/// 展開対象のライブラリクレート
+#[derive(Debug, Clone)]
+pub struct Library {
+    /// ソースコード中でライブラリクレートを参照する名前
+    pub name: String,
+    /// ライブラリクレートのルートのファイルへのパス
+    pub root_path: PathBuf,
+}
+
+impl Library {
+    /// 名前はクレート名として正規化する
+    pub fn new(name: &str, root_path: PathBuf) -> Library {
+        Library {
+            name: crate_ident(name),
+            root_path,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ModuleItemAccessibility {
     Direct(ModuleItemPath),
@@ -37,45 +72,44 @@ pub enum ModuleItemPath {
 
 pub fn make_module_item_path(
     module_parts: &[String],
-    package_name: &str,
-    package_src_path: &PathBuf,
-    crate_path: &PathBuf,
-    self_path: &PathBuf,
-) -> Result<ModuleItemPath, String> {
+    libraries: &[Library],
+    crate_path: &Path,
+    self_path: &Path,
+) -> Result<ModuleItemPath, ExpunchError> {
     let mut lib_file = None;
     let mut path_buf = PathBuf::new();
 
     let resolved_parts = module_parts
         .iter()
         .filter_map(|module_part| match module_part {
-            _ if module_part == "crate" => None,
             _ if module_part == "self" => None,
             _ => Some(String::from(module_part)),
         })
         .collect();
 
-    for module_part in module_parts.iter() {
+    for (index, module_part) in module_parts.iter().enumerate() {
         lib_file = None;
+        // 先頭のライブラリクレートの名前はそのルートのファイルに解決する
+        if let Some(library) = libraries
+            .iter()
+            .find(|library| index == 0 && &library.name == module_part)
+        {
+            lib_file = Some(library.root_path.clone());
+            path_buf.push(make_module_dir(&library.root_path, true));
+            continue;
+        }
         path_buf.push(match module_part {
-            _ if module_part == "crate" => crate_path.clone(),
-            _ if module_part == package_name => {
-                lib_file = Some(package_src_path.join("lib.rs"));
-                package_src_path.clone()
-            }
+            _ if module_part == "crate" => make_module_dir(crate_path, true),
             _ if module_part == "super" => self_path
                 .parent()
-                .ok_or_else(|| {
-                    format!(
-                        "Failed to get the parent directory of the {0}
-{1} より上の階層へ遡ろうとしました",
-                        self_path.to_str().unwrap_or("(undisplayable path)"),
-                        self_path.to_str().unwrap_or("（表示できないパス）"),
-                    )
+                .ok_or_else(|| ExpunchError::AboveRoot {
+                    path: self_path.to_path_buf(),
+                    module_parts: module_parts.to_vec(),
                 })?
                 .to_path_buf(),
             _ if module_part == "self" => {
                 if path_buf.as_os_str().is_empty() {
-                    self_path.clone()
+                    self_path.to_path_buf()
                 } else {
                     continue;
                 }
@@ -106,59 +140,356 @@ pub fn make_module_item_path(
     )
 }
 
+/// ソースコードのファイルを読み込む
+///
+/// # Arguments
+///
+/// * `source_path` ソースコードへのパス
+///
+/// * `module_parts` エラーの報告に用いるモジュールのパス
+pub fn read_source_file(
+    source_path: &Path,
+    module_parts: &[String],
+) -> Result<String, ExpunchError> {
+    let mut file = File::open(source_path).map_err(|source| ExpunchError::FileNotFound {
+        path: source_path.to_path_buf(),
+        module_parts: module_parts.to_vec(),
+        source,
+    })?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .map_err(|source| ExpunchError::ReadFailed {
+            path: source_path.to_path_buf(),
+            module_parts: module_parts.to_vec(),
+            source,
+        })?;
+    Ok(content)
+}
+
+/// `include_bytes!`などで取り込むファイルをバイト列として読み込む
+///
+/// # Arguments
+///
+/// * `path` 取り込むファイルへのパス
+///
+/// * `module_parts` エラーの報告に用いるモジュールのパス
+pub fn read_include_file(path: &Path, module_parts: &[String]) -> Result<Vec<u8>, ExpunchError> {
+    let mut file = File::open(path).map_err(|source| ExpunchError::FileNotFound {
+        path: path.to_path_buf(),
+        module_parts: module_parts.to_vec(),
+        source,
+    })?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)
+        .map_err(|source| ExpunchError::ReadFailed {
+            path: path.to_path_buf(),
+            module_parts: module_parts.to_vec(),
+            source,
+        })?;
+    Ok(content)
+}
+
+/// 読み込んだソースコードを構文解析する
+///
+/// # Arguments
+///
+/// * `content` ソースコードの内容
+///
+/// * `source_path` エラーの報告に用いるソースコードへのパス
+///
+/// * `module_parts` エラーの報告に用いるモジュールのパス
+pub fn parse_source_code(
+    content: &str,
+    source_path: &Path,
+    module_parts: &[String],
+) -> Result<syn::File, ExpunchError> {
+    syn::parse_file(content).map_err(|source| ExpunchError::ParseFailed {
+        path: source_path.to_path_buf(),
+        module_parts: module_parts.to_vec(),
+        source,
+    })
+}
+
+/// ソースコードのファイルを読み込んで構文解析する
+///
+/// # Arguments
+///
+/// * `source_path` ソースコードへのパス
+///
+/// * `module_parts` エラーの報告に用いるモジュールのパス
+pub fn parse_source_file(
+    source_path: &Path,
+    module_parts: &[String],
+) -> Result<syn::File, ExpunchError> {
+    let content = read_source_file(source_path, module_parts)?;
+    parse_source_code(&content, source_path, module_parts)
+}
+
+/// `#[path = "..."]`属性で指定されたパスを取得する
+pub fn path_attribute(attrs: &[Attribute]) -> Option<String> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("path"))
+        .find_map(|attr| match attr.parse_meta() {
+            Ok(Meta::NameValue(meta)) => match meta.lit {
+                Lit::Str(lit) => Some(lit.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+}
+
+/// アイテムに付与された属性を取得する
+pub fn item_attributes(item: &Item) -> &[Attribute] {
+    match item {
+        Item::Const(item) => &item.attrs,
+        Item::Enum(item) => &item.attrs,
+        Item::ExternCrate(item) => &item.attrs,
+        Item::Fn(item) => &item.attrs,
+        Item::ForeignMod(item) => &item.attrs,
+        Item::Impl(item) => &item.attrs,
+        Item::Macro(item) => &item.attrs,
+        Item::Macro2(item) => &item.attrs,
+        Item::Mod(item) => &item.attrs,
+        Item::Static(item) => &item.attrs,
+        Item::Struct(item) => &item.attrs,
+        Item::Trait(item) => &item.attrs,
+        Item::TraitAlias(item) => &item.attrs,
+        Item::Type(item) => &item.attrs,
+        Item::Union(item) => &item.attrs,
+        Item::Use(item) => &item.attrs,
+        _ => &[],
+    }
+}
+
+/// `#[test]`属性または`#[cfg(test)]`属性が付与されたテスト専用のアイテムか
+pub fn is_test_item(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(|attr| {
+        attr.path.is_ident("test")
+            || (attr.path.is_ident("cfg")
+                && matches!(attr.parse_meta(), Ok(Meta::List(list))
+                    if list.nested.len() == 1
+                        && matches!(list.nested.first(), Some(NestedMeta::Meta(Meta::Path(path)))
+                            if path.is_ident("test"))))
+    })
+}
+
+/// クレートのルートでのみ有効な内部属性か
+///
+/// `cfg_attr`は展開される属性のいずれかがクレートのルートでのみ有効な場合に該当する
+pub fn is_crate_level_attribute(attr: &Attribute) -> bool {
+    match attr.parse_meta() {
+        Ok(meta) => is_crate_level_meta(&meta),
+        Err(_) => false,
+    }
+}
+
+fn is_crate_level_meta(meta: &Meta) -> bool {
+    const CRATE_LEVEL_ATTRIBUTES: [&str; 9] = [
+        "no_std",
+        "no_main",
+        "no_builtins",
+        "crate_name",
+        "crate_type",
+        "feature",
+        "recursion_limit",
+        "type_length_limit",
+        "windows_subsystem",
+    ];
+    match meta {
+        Meta::List(list) if list.path.is_ident("cfg_attr") => list
+            .nested
+            .iter()
+            .skip(1)
+            .any(|nested| matches!(nested, NestedMeta::Meta(meta) if is_crate_level_meta(meta))),
+        // `#![doc(html_root_url = "...")]`などのドキュメントの設定
+        Meta::List(list) if list.path.is_ident("doc") => list.nested.iter().any(|nested| {
+            matches!(nested, NestedMeta::Meta(meta)
+            if meta.path().get_ident().is_some_and(|ident| {
+                let ident = ident.to_string();
+                ident.starts_with("html_") || ident == "test" || ident == "issue_tracker_base_url"
+            }))
+        }),
+        _ => CRATE_LEVEL_ATTRIBUTES
+            .iter()
+            .any(|name| meta.path().is_ident(name)),
+    }
+}
+
+/// use文によって導入される名前を取得する
+///
+/// グロブや`_`への別名など名前を特定できない場合は`false`を返す
+pub fn use_names(use_tree: &UseTree, parent: Option<&str>, names: &mut Vec<String>) -> bool {
+    match use_tree {
+        UseTree::Path(use_path) => {
+            let parent = use_path.ident.to_string();
+            use_names(&use_path.tree, Some(&parent), names)
+        }
+        UseTree::Name(use_name) => {
+            if use_name.ident == "self" {
+                names.extend(parent.map(String::from));
+            } else {
+                names.push(use_name.ident.to_string());
+            }
+            true
+        }
+        UseTree::Rename(use_rename) => {
+            names.push(use_rename.rename.to_string());
+            use_rename.rename != "_"
+        }
+        UseTree::Glob(_) => false,
+        UseTree::Group(use_group) => {
+            // 名前を特定できない要素があってもすべての名前を収集する
+            let mut is_named = true;
+            for item in &use_group.items {
+                is_named &= use_names(item, parent, names);
+            }
+            is_named
+        }
+    }
+}
+
+/// モジュール直下のアイテムによって定義される名前を取得する
+///
+/// グロブなどで名前を特定できない場合は`None`を返す
+pub fn defined_names(items: &[Item]) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+    for item in items {
+        match item {
+            Item::Const(item) => names.push(item.ident.to_string()),
+            Item::Enum(item) => names.push(item.ident.to_string()),
+            Item::ExternCrate(item) => names.push(
+                item.rename
+                    .as_ref()
+                    .map_or(&item.ident, |(_, rename)| rename)
+                    .to_string(),
+            ),
+            Item::Fn(item) => names.push(item.sig.ident.to_string()),
+            Item::Macro(item) => match &item.ident {
+                Some(ident) => names.push(ident.to_string()),
+                // マクロの呼び出しが定義する名前は特定できない
+                None => return None,
+            },
+            Item::Macro2(item) => names.push(item.ident.to_string()),
+            Item::Mod(item) => names.push(item.ident.to_string()),
+            Item::Static(item) => names.push(item.ident.to_string()),
+            Item::Struct(item) => names.push(item.ident.to_string()),
+            Item::Trait(item) => names.push(item.ident.to_string()),
+            Item::TraitAlias(item) => names.push(item.ident.to_string()),
+            Item::Type(item) => names.push(item.ident.to_string()),
+            Item::Union(item) => names.push(item.ident.to_string()),
+            // 注：use文で定義される名前はガードの評価時に収集される
+            Item::Use(item) if !use_names(&item.tree, None, &mut names) => return None,
+            Item::Verbatim(_) | Item::ForeignMod(_) => return None,
+            _ => {}
+        }
+    }
+    Some(names)
+}
+
+/// ファイル中のmod宣言を解決するディレクトリを取得する
+///
+/// `mod.rs`やクレートルートのファイルはそのファイルのディレクトリ、
+/// それ以外の`foo.rs`は`foo`ディレクトリとなる
+pub fn make_module_dir(source_path: &Path, is_mod_rs: bool) -> PathBuf {
+    let parent = source_path.parent().unwrap_or_else(|| Path::new(""));
+    match source_path.file_stem() {
+        Some(stem) if !is_mod_rs && source_path.file_name() != Some("mod.rs".as_ref()) => {
+            parent.join(stem)
+        }
+        _ => parent.to_path_buf(),
+    }
+}
+
+/// mod宣言に対応するファイルのパスと、そのファイル中のmod宣言を解決するディレクトリを取得する
+///
+/// # Arguments
+///
+/// * `name` 宣言されたモジュールの名前
+///
+/// * `path_attr` `#[path = "..."]`属性で指定されたパス
+///
+/// * `source_dir` 宣言したファイルのディレクトリ（`#[path]`属性の基準）
+///
+/// * `module_dir` 宣言したファイル中のmod宣言を解決するディレクトリ
+pub fn make_declared_module_path(
+    name: &str,
+    path_attr: Option<&str>,
+    source_dir: &Path,
+    module_dir: &Path,
+) -> Option<(PathBuf, PathBuf)> {
+    if let Some(path_attr) = path_attr {
+        // rustcと同様に`#[path]`属性で読み込むファイルはmod.rsと同様に扱う
+        let path = source_dir.join(path_attr);
+        return if path.is_file() {
+            let nested_module_dir = make_module_dir(&path, true);
+            Some((path, nested_module_dir))
+        } else {
+            None
+        };
+    }
+
+    let module_name_file = module_dir.join(name).with_extension("rs");
+    let mod_file = module_dir.join(name).join("mod.rs");
+    if module_name_file.is_file() {
+        Some((module_name_file, module_dir.join(name)))
+    } else if mod_file.is_file() {
+        Some((mod_file, module_dir.join(name)))
+    } else {
+        None
+    }
+}
+
 pub fn collect_module_items(
     use_tree: &UseTree,
-    package_name: &str,
-    package_src_path: &PathBuf,
-    crate_name: &str,
-    crate_path: &PathBuf,
-    self_path: &PathBuf,
-) -> Result<Vec<ModuleItemAccessibility>, String> {
-    let mut module_path_map = HashMap::new();
+    libraries: &[Library],
+    crate_path: &Path,
+    self_path: &Path,
+) -> Result<Vec<ModuleItemAccessibility>, ExpunchError> {
+    let mut module_path_map = Vec::new();
     collect_module_items_impl(
         use_tree,
         &mut Vec::new(),
-        package_name,
-        package_src_path,
-        crate_name,
+        libraries,
         crate_path,
         self_path,
         &mut module_path_map,
     )?;
-    Ok(module_path_map.values().cloned().collect())
+    Ok(module_path_map
+        .into_iter()
+        .map(|(_, module_item)| module_item)
+        .collect())
 }
 
 fn collect_module_items_impl(
     use_tree: &UseTree,
     module_parts: &mut Vec<String>,
-    package_name: &str,
-    package_src_path: &PathBuf,
-    crate_name: &str,
-    crate_path: &PathBuf,
-    self_path: &PathBuf,
-    module_path_map: &mut HashMap<Vec<String>, ModuleItemAccessibility>,
-) -> Result<(), String> {
+    libraries: &[Library],
+    crate_path: &Path,
+    self_path: &Path,
+    module_path_map: &mut Vec<(Vec<String>, ModuleItemAccessibility)>,
+) -> Result<(), ExpunchError> {
     match use_tree {
         UseTree::Path(use_path) => {
             let name = use_path.ident.to_string();
             module_parts.push(name);
 
-            module_path_map.entry(module_parts.clone()).or_insert(
-                ModuleItemAccessibility::Indirect(make_module_item_path(
-                    module_parts,
-                    package_name,
-                    package_src_path,
-                    crate_path,
-                    self_path,
-                )?),
-            );
+            if !contains_module_parts(module_path_map, module_parts) {
+                module_path_map.push((
+                    module_parts.clone(),
+                    ModuleItemAccessibility::Indirect(make_module_item_path(
+                        module_parts,
+                        libraries,
+                        crate_path,
+                        self_path,
+                    )?),
+                ));
+            }
 
             collect_module_items_impl(
                 &use_path.tree,
                 module_parts,
-                package_name,
-                package_src_path,
-                crate_name,
+                libraries,
                 crate_path,
                 self_path,
                 module_path_map,
@@ -170,15 +501,17 @@ fn collect_module_items_impl(
             let name = use_name.ident.to_string();
             module_parts.push(name);
 
-            module_path_map
-                .entry(module_parts.clone())
-                .or_insert(ModuleItemAccessibility::Direct(make_module_item_path(
-                    module_parts,
-                    package_name,
-                    package_src_path,
-                    crate_path,
-                    self_path,
-                )?));
+            if !contains_module_parts(module_path_map, module_parts) {
+                module_path_map.push((
+                    module_parts.clone(),
+                    ModuleItemAccessibility::Direct(make_module_item_path(
+                        module_parts,
+                        libraries,
+                        crate_path,
+                        self_path,
+                    )?),
+                ));
+            }
 
             module_parts.pop();
         }
@@ -186,15 +519,17 @@ fn collect_module_items_impl(
             let name = use_rename.ident.to_string();
             module_parts.push(name);
 
-            module_path_map
-                .entry(module_parts.clone())
-                .or_insert(ModuleItemAccessibility::Direct(make_module_item_path(
-                    module_parts,
-                    package_name,
-                    package_src_path,
-                    crate_path,
-                    self_path,
-                )?));
+            if !contains_module_parts(module_path_map, module_parts) {
+                module_path_map.push((
+                    module_parts.clone(),
+                    ModuleItemAccessibility::Direct(make_module_item_path(
+                        module_parts,
+                        libraries,
+                        crate_path,
+                        self_path,
+                    )?),
+                ));
+            }
 
             module_parts.pop();
         }
@@ -203,9 +538,7 @@ fn collect_module_items_impl(
                 collect_module_items_impl(
                     item,
                     module_parts,
-                    package_name,
-                    package_src_path,
-                    crate_name,
+                    libraries,
                     crate_path,
                     self_path,
                     module_path_map,
@@ -216,3 +549,35 @@ fn collect_module_items_impl(
     };
     Ok(())
 }
+
+/// `a::b::c`のようなパスのセグメントから依存するモジュールを取得する
+///
+/// 末尾以外のセグメントは途中に現れるモジュールとして扱う
+pub fn collect_path_module_items(
+    segments: &[String],
+    libraries: &[Library],
+    crate_path: &Path,
+    self_path: &Path,
+) -> Result<Vec<ModuleItemAccessibility>, ExpunchError> {
+    let mut module_items = Vec::new();
+    for length in 1..=segments.len() {
+        let module_item_path =
+            make_module_item_path(&segments[..length], libraries, crate_path, self_path)?;
+        module_items.push(if length == segments.len() {
+            ModuleItemAccessibility::Direct(module_item_path)
+        } else {
+            ModuleItemAccessibility::Indirect(module_item_path)
+        });
+    }
+    Ok(module_items)
+}
+
+/// 既に同じモジュールパスが登録されているか
+fn contains_module_parts(
+    module_path_map: &[(Vec<String>, ModuleItemAccessibility)],
+    module_parts: &[String],
+) -> bool {
+    module_path_map
+        .iter()
+        .any(|(parts, _)| parts == module_parts)
+}
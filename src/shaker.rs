@@ -0,0 +1,374 @@
+use crate::error::ExpunchError;
+use crate::expuncher::{ModuleNode, ReplacementSpan};
+use crate::module::{is_test_item, item_attributes, parse_source_code, use_names};
+use proc_macro2::{LineColumn, TokenStream, TokenTree};
+use quote::ToTokens;
+use std::collections::HashSet;
+use syn::visit::{self, Visit};
+use syn::{self, spanned::Spanned, Item, ItemImpl, ItemMod, ItemUse, TraitItem, UseTree};
+
+/// 刈り込みの対象となるアイテム
+#[derive(Debug)]
+struct Candidate {
+    /// アイテムを含むファイルのモジュールのパス
+    module_parts: Vec<String>,
+    /// アイテムを直接含むインラインモジュールまでのモジュールのパス
+    module_path: Vec<String>,
+    start: LineColumn,
+    end: LineColumn,
+    /// アイテムが定義する名前
+    names: Vec<String>,
+    /// トレイトの場合にそのメソッドの名前
+    methods: Vec<String>,
+    /// implの場合に対象の型やトレイトに現れる名前
+    anchors: Option<HashSet<String>>,
+    /// アイテムが参照する名前
+    references: HashSet<String>,
+    /// 参照の有無に関わらず残すか
+    always: bool,
+}
+
+/// ライブラリのモジュールから到達不能なアイテムを取り除く
+///
+/// 名前に基づいて到達可能性を保守的に判定し、到達不能なアイテムを削除する置換を各モジュールに追加する
+///
+/// # Arguments
+///
+/// * `root` ルートのモジュールのノード
+///
+/// * `library_parts` 刈り込みの対象となるモジュールのパスの一覧
+pub fn shake(root: &mut ModuleNode, library_parts: &[Vec<String>]) -> Result<(), ExpunchError> {
+    let mut candidates = Vec::new();
+    let mut reached = HashSet::new();
+    let mut glob_targets = Vec::new();
+    collect(
+        root,
+        &mut Vec::new(),
+        library_parts,
+        &[],
+        &mut candidates,
+        &mut reached,
+        &mut glob_targets,
+    )?;
+
+    // グロブインポートされたモジュールで定義されている名前はすべて起点とする
+    for candidate in &candidates {
+        if glob_targets.contains(&candidate.module_path) {
+            reached.extend(candidate.names.iter().cloned());
+        }
+    }
+
+    // ライブラリで定義されている名前
+    let library_names: HashSet<&String> = candidates
+        .iter()
+        .flat_map(|candidate| candidate.names.iter())
+        .collect();
+
+    // 到達可能なアイテムが変化しなくなるまで繰り返す
+    let mut kept = vec![false; candidates.len()];
+    loop {
+        let mut changed = false;
+        for (index, candidate) in candidates.iter().enumerate() {
+            if kept[index] {
+                continue;
+            }
+
+            let is_reachable = candidate.always
+                || candidate.names.iter().any(|name| reached.contains(name))
+                // メソッドの呼び出しはトレイトの名前を含まないのでメソッドの名前を参照とみなす
+                || candidate.methods.iter().any(|name| reached.contains(name))
+                || candidate.anchors.as_ref().is_some_and(|anchors| {
+                    anchors
+                        .iter()
+                        .filter(|name| library_names.contains(name))
+                        .all(|name| reached.contains(name))
+                });
+            if is_reachable {
+                kept[index] = true;
+                changed = true;
+                reached.extend(candidate.references.iter().cloned());
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // 到達不能なアイテムを削除する置換を追加
+    for (candidate, _) in candidates.iter().zip(kept).filter(|(_, is_kept)| !is_kept) {
+        if let Some(node) = root.node_mut(&candidate.module_parts) {
+            // 削除するアイテムの内部の置換は不要
+            node.replacement_spans
+                .retain(|span| span.start < candidate.start || candidate.end < span.end);
+            node.replacement_spans
+                .push(ReplacementSpan::removal(candidate.start, candidate.end));
+        }
+    }
+
+    Ok(())
+}
+
+/// モジュールのノードを再帰的に辿って刈り込みの対象となるアイテムと起点となる名前を収集する
+///
+/// # Arguments
+///
+/// * `crate_parts` ノードを含むクレートのルートのモジュールのパス
+///
+/// * `glob_targets` グロブインポートの対象となるモジュールのパスの収集先
+fn collect(
+    node: &ModuleNode,
+    module_parts: &mut Vec<String>,
+    library_parts: &[Vec<String>],
+    crate_parts: &[String],
+    candidates: &mut Vec<Candidate>,
+    reached: &mut HashSet<String>,
+    glob_targets: &mut Vec<Vec<String>>,
+) -> Result<(), ExpunchError> {
+    let crate_parts = if library_parts.contains(module_parts) {
+        module_parts.clone()
+    } else {
+        crate_parts.to_vec()
+    };
+    let is_library = !crate_parts.is_empty();
+
+    if let Some(path) = &node.path {
+        let content = node.read_source(path, module_parts)?;
+        let ast = parse_source_code(&content, path, module_parts)?;
+        let mut collector = GlobCollector {
+            module_path: module_parts.clone(),
+            crate_parts: &crate_parts,
+            library_parts,
+            targets: glob_targets,
+        };
+        if is_library {
+            collect_candidates(node, &ast.items, module_parts, &mut collector, candidates);
+        } else {
+            // ライブラリ以外のソースコードで参照される名前を起点とする
+            collect_root_idents(node, &ast.items, &mut collector, reached);
+        }
+    }
+
+    for (name, child) in &node.children {
+        module_parts.push(name.clone());
+        collect(
+            child,
+            module_parts,
+            library_parts,
+            &crate_parts,
+            candidates,
+            reached,
+            glob_targets,
+        )?;
+        module_parts.pop();
+    }
+
+    Ok(())
+}
+
+/// アイテムの一覧から刈り込みの対象となるアイテムを収集する
+///
+/// インラインモジュールは残し、その内部のアイテムを再帰的に対象とする
+fn collect_candidates(
+    node: &ModuleNode,
+    items: &[Item],
+    module_parts: &[String],
+    collector: &mut GlobCollector,
+    candidates: &mut Vec<Candidate>,
+) {
+    for item in items.iter().filter(|item| !is_removed(node, item)) {
+        let mut names = Vec::new();
+        let mut methods = Vec::new();
+        let mut anchors = None;
+        let mut always = false;
+
+        match item {
+            Item::Mod(item_mod) => {
+                if let Some((_, items)) = &item_mod.content {
+                    collector.module_path.push(item_mod.ident.to_string());
+                    collect_candidates(node, items, module_parts, collector, candidates);
+                    collector.module_path.pop();
+                }
+                continue;
+            }
+            Item::Const(item) => {
+                names.push(item.ident.to_string());
+                always = item.ident == "_";
+            }
+            Item::Enum(item) => names.push(item.ident.to_string()),
+            Item::Fn(item) => names.push(item.sig.ident.to_string()),
+            Item::Impl(item) => anchors = Some(impl_anchors(item)),
+            Item::Macro(item) => match &item.ident {
+                Some(ident) => names.push(ident.to_string()),
+                None => always = true,
+            },
+            Item::Macro2(item) => names.push(item.ident.to_string()),
+            Item::Static(item) => names.push(item.ident.to_string()),
+            Item::Struct(item) => names.push(item.ident.to_string()),
+            Item::Trait(item) => {
+                names.push(item.ident.to_string());
+                methods.extend(item.items.iter().filter_map(|trait_item| match trait_item {
+                    TraitItem::Method(method) => Some(method.sig.ident.to_string()),
+                    _ => None,
+                }));
+            }
+            Item::TraitAlias(item) => names.push(item.ident.to_string()),
+            Item::Type(item) => names.push(item.ident.to_string()),
+            Item::Union(item) => names.push(item.ident.to_string()),
+            Item::Use(item) => {
+                // 外部のクレートのuse文はトレイトのメソッドのために暗黙的に使用され得るので残す
+                always = item.leading_colon.is_some()
+                    || matches!(&item.tree, UseTree::Path(path)
+                        if path.ident == "std" || path.ident == "core" || path.ident == "alloc");
+                always |= !use_names(&item.tree, None, &mut names);
+            }
+            _ => always = true,
+        }
+
+        // 残されたテスト専用のアイテムは起点として扱う
+        always |= is_test_item(item_attributes(item));
+
+        let mut references = HashSet::new();
+        collect_idents(item.to_token_stream(), &mut references);
+        collector.visit_item(item);
+
+        let span = item.span();
+        candidates.push(Candidate {
+            module_parts: module_parts.to_vec(),
+            module_path: collector.module_path.clone(),
+            start: span.start(),
+            end: span.end(),
+            names,
+            methods,
+            anchors,
+            references,
+            always,
+        });
+    }
+}
+
+/// 削除済みのアイテムを除いて識別子とグロブインポートの対象を収集する
+fn collect_root_idents(
+    node: &ModuleNode,
+    items: &[Item],
+    collector: &mut GlobCollector,
+    idents: &mut HashSet<String>,
+) {
+    for item in items.iter().filter(|item| !is_removed(node, item)) {
+        match item {
+            Item::Mod(ItemMod {
+                ident,
+                content: Some((_, items)),
+                ..
+            }) => {
+                collector.module_path.push(ident.to_string());
+                collect_root_idents(node, items, collector, idents);
+                collector.module_path.pop();
+            }
+            _ => {
+                collect_idents(item.to_token_stream(), idents);
+                collector.visit_item(item);
+            }
+        }
+    }
+}
+
+/// use文のグロブインポートの対象となるモジュールのパスを収集する
+struct GlobCollector<'a> {
+    /// 走査中のモジュールのパス
+    module_path: Vec<String>,
+    /// 走査中のモジュールを含むクレートのルートのモジュールのパス
+    crate_parts: &'a [String],
+    library_parts: &'a [Vec<String>],
+    targets: &'a mut Vec<Vec<String>>,
+}
+
+impl GlobCollector<'_> {
+    /// use文のパスの木を辿ってグロブインポートの対象を登録する
+    fn collect_use_tree(&mut self, use_tree: &UseTree, segments: &mut Vec<String>) {
+        match use_tree {
+            UseTree::Path(path) => {
+                segments.push(path.ident.to_string());
+                self.collect_use_tree(&path.tree, segments);
+                segments.pop();
+            }
+            UseTree::Group(group) => {
+                for item in &group.items {
+                    self.collect_use_tree(item, segments);
+                }
+            }
+            UseTree::Glob(_) => {
+                let target = self.resolve(segments);
+                if !self.targets.contains(&target) {
+                    self.targets.push(target);
+                }
+            }
+            UseTree::Name(_) | UseTree::Rename(_) => {}
+        }
+    }
+
+    /// use文のパスを展開後のモジュールのパスに解決する
+    fn resolve(&self, segments: &[String]) -> Vec<String> {
+        match segments.first().map(String::as_str) {
+            Some("crate") => [self.crate_parts, &segments[1..]].concat(),
+            Some("self") => [&self.module_path, &segments[1..]].concat(),
+            Some("super") => {
+                let count = segments
+                    .iter()
+                    .take_while(|segment| *segment == "super")
+                    .count();
+                let len = self.module_path.len().saturating_sub(count);
+                [&self.module_path[..len], &segments[count..]].concat()
+            }
+            Some(first)
+                if self
+                    .library_parts
+                    .iter()
+                    .any(|library| library.len() == 1 && library[0] == first) =>
+            {
+                segments.to_vec()
+            }
+            _ => [&self.module_path, segments].concat(),
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for GlobCollector<'_> {
+    fn visit_item_use(&mut self, item_use: &'ast ItemUse) {
+        self.collect_use_tree(&item_use.tree, &mut Vec::new());
+    }
+
+    fn visit_item_mod(&mut self, item_mod: &'ast ItemMod) {
+        self.module_path.push(item_mod.ident.to_string());
+        visit::visit_item_mod(self, item_mod);
+        self.module_path.pop();
+    }
+}
+
+/// アイテムがテスト専用のアイテムなどとして既に削除されているか
+fn is_removed(node: &ModuleNode, item: &Item) -> bool {
+    let span = item.span();
+    node.is_removed(span.start(), span.end())
+}
+
+/// implの対象の型とトレイトに現れる名前を取得する
+fn impl_anchors(item_impl: &ItemImpl) -> HashSet<String> {
+    let mut anchors = HashSet::new();
+    collect_idents(item_impl.self_ty.to_token_stream(), &mut anchors);
+    if let Some((_, path, _)) = &item_impl.trait_ {
+        collect_idents(path.to_token_stream(), &mut anchors);
+    }
+    anchors
+}
+
+/// トークン列に現れる識別子を収集する
+fn collect_idents(tokens: TokenStream, idents: &mut HashSet<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                idents.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => collect_idents(group.stream(), idents),
+            _ => {}
+        }
+    }
+}
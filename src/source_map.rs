@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// 展開したソースコードの連続する行と元のファイルの行の対応
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMapping {
+    /// 展開したソースコードの開始行（1始まり）
+    pub output_start: usize,
+    /// 展開したソースコードの終了行（1始まり、この行を含む）
+    pub output_end: usize,
+    /// 元のファイルへのパス
+    pub path: PathBuf,
+    /// 元のファイルのモジュールのパス
+    pub module_parts: Vec<String>,
+    /// `output_start`に対応する元のファイルの行（1始まり）
+    pub source_start: usize,
+}
+
+impl SourceMapping {
+    /// 展開したソースコードの行に対応する元のファイルの行を取得する
+    pub fn source_line(&self, output_line: usize) -> Option<usize> {
+        if self.output_start <= output_line && output_line <= self.output_end {
+            Some(self.source_start + (output_line - self.output_start))
+        } else {
+            None
+        }
+    }
+}
+
+/// 展開したソースコードの行を元のファイルの行に対応付けるソースマップ
+///
+/// 置換により生成された行やモジュールの宣言の行は対応を持たない
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceMap {
+    pub mappings: Vec<SourceMapping>,
+}
+
+impl SourceMap {
+    pub fn new(mappings: Vec<SourceMapping>) -> SourceMap {
+        SourceMap { mappings }
+    }
+
+    /// 展開したソースコードの行に対応する元のファイルへのパスと行を取得する
+    pub fn locate(&self, output_line: usize) -> Option<(&Path, usize)> {
+        self.mappings.iter().find_map(|mapping| {
+            mapping
+                .source_line(output_line)
+                .map(|source_line| (mapping.path.as_path(), source_line))
+        })
+    }
+}
+
+/// 書き込んだ行数を数えながら元のファイルの行との対応を記録する出力先
+pub struct MappedWriter<W: Write> {
+    inner: W,
+    /// 現在書き込み中の行（1始まり）
+    line: usize,
+    mappings: Vec<SourceMapping>,
+}
+
+impl<W: Write> MappedWriter<W> {
+    /// # Arguments
+    ///
+    /// * `line` 最初に書き込む行の行番号
+    pub fn new(inner: W, line: usize) -> MappedWriter<W> {
+        MappedWriter {
+            inner,
+            line,
+            mappings: Vec::new(),
+        }
+    }
+
+    /// 現在書き込み中の行
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// 元のファイルの内容をそのまま書き込んで行の対応を記録する
+    ///
+    /// # Arguments
+    ///
+    /// * `source_line` `content`の先頭の元のファイルでの行
+    pub fn write_source(
+        &mut self,
+        content: &str,
+        path: &Path,
+        module_parts: &[String],
+        source_line: usize,
+    ) -> io::Result<()> {
+        if content.is_empty() {
+            return Ok(());
+        }
+
+        // 改行文字で終わる場合は次の行を含めない
+        let line_count = content.matches('\n').count();
+        let output_end = if content.ends_with('\n') {
+            self.line + line_count - 1
+        } else {
+            self.line + line_count
+        };
+        self.push_mapping(SourceMapping {
+            output_start: self.line,
+            output_end,
+            path: path.to_path_buf(),
+            module_parts: module_parts.to_vec(),
+            source_start: source_line,
+        });
+        self.write_all(content.as_bytes())
+    }
+
+    /// 対応を追加する
+    ///
+    /// 直前の対応と行が連続する場合はまとめる
+    pub fn push_mapping(&mut self, mapping: SourceMapping) {
+        if let Some(last) = self.mappings.last_mut() {
+            if last.path == mapping.path
+                && last.module_parts == mapping.module_parts
+                && last.output_start <= mapping.output_start
+                && mapping.output_start <= last.output_end + 1
+                && last.source_start + (mapping.output_start - last.output_start)
+                    == mapping.source_start
+            {
+                last.output_end = last.output_end.max(mapping.output_end);
+                return;
+            }
+        }
+        self.mappings.push(mapping);
+    }
+
+    /// 出力先と記録した対応を取得する
+    pub fn into_parts(self) -> (W, Vec<SourceMapping>) {
+        (self.inner, self.mappings)
+    }
+}
+
+impl<W: Write> Write for MappedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.line += buf[..written].iter().filter(|&&byte| byte == b'\n').count();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,246 @@
+use cargo_expunch::cfg::CfgSet;
+use cargo_expunch::expuncher::Expuncher;
+use std::fs;
+use std::path::{Path, PathBuf};
+use syn::{ItemFn, NestedMeta};
+
+/// 一時ディレクトリにライブラリクレートのファイルを作成する
+fn make_library(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("cargo-expunch-cfg-{}-{}", name, std::process::id()));
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    dir
+}
+
+/// 設定の集合を用いてライブラリクレートを参照するソースコードを展開する
+///
+/// 比較しやすいように空白を取り除いて返す
+fn expunch(dir: &Path, main: &str, specs: &[&str]) -> String {
+    let mut cfg = CfgSet::new();
+    for spec in specs {
+        cfg.insert_spec(spec);
+    }
+    let mut expuncher = Expuncher::new("my_lib", dir.join("src/lib.rs"));
+    expuncher.set_tree_shaking(false);
+    expuncher.set_cfg(cfg);
+    expuncher
+        .analyze_source_code(&dir.join("src/main.rs"), main.to_string())
+        .unwrap();
+    expuncher
+        .to_string()
+        .unwrap()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+/// 設定の集合を作成する
+fn make_cfg(specs: &[&str]) -> CfgSet {
+    let mut cfg = CfgSet::new();
+    for spec in specs {
+        cfg.insert_spec(spec);
+    }
+    cfg
+}
+
+/// `cfg`の述語を評価する
+fn evaluate(cfg: &CfgSet, predicate: &str) -> Option<bool> {
+    cfg.evaluate(&syn::parse_str::<NestedMeta>(predicate).unwrap())
+}
+
+#[test]
+fn evaluates_features() {
+    let cfg = make_cfg(&["feature=\"a\""]);
+
+    assert_eq!(evaluate(&cfg, "feature = \"a\""), Some(true));
+    assert_eq!(evaluate(&cfg, "feature = \"b\""), Some(false));
+    assert_eq!(evaluate(&cfg, "not(feature = \"b\")"), Some(true));
+    assert_eq!(
+        evaluate(&cfg, "all(feature = \"a\", feature = \"b\")"),
+        Some(false)
+    );
+    assert_eq!(
+        evaluate(&cfg, "any(feature = \"a\", feature = \"b\")"),
+        Some(true)
+    );
+
+    // フィーチャーは指定がなくても評価する
+    let cfg = make_cfg(&["custom"]);
+    assert_eq!(evaluate(&cfg, "feature = \"a\""), Some(false));
+    assert_eq!(evaluate(&cfg, "custom"), Some(true));
+}
+
+#[test]
+fn leaves_unknown_predicates_unevaluated() {
+    let cfg = make_cfg(&["feature=\"a\""]);
+
+    assert_eq!(evaluate(&cfg, "unix"), None);
+    assert_eq!(evaluate(&cfg, "target_os = \"linux\""), None);
+    assert_eq!(evaluate(&cfg, "not(debug_assertions)"), None);
+    assert_eq!(evaluate(&cfg, "all(unix, feature = \"a\")"), None);
+    assert_eq!(evaluate(&cfg, "all(unix, feature = \"b\")"), Some(false));
+    assert_eq!(evaluate(&cfg, "any(unix, feature = \"a\")"), Some(true));
+    assert_eq!(evaluate(&cfg, "any(unix, feature = \"b\")"), None);
+
+    let cfg = make_cfg(&["unix"]);
+    assert_eq!(evaluate(&cfg, "unix"), Some(true));
+    assert_eq!(evaluate(&cfg, "windows"), None);
+}
+
+#[test]
+fn expands_cfg_attr() {
+    let cfg = make_cfg(&["feature=\"a\""]);
+    let item: ItemFn = syn::parse_str(
+        "#[cfg_attr(feature = \"a\", allow(unused), inline)] \
+         #[cfg_attr(feature = \"b\", inline)] \
+         #[cfg_attr(unix, cold)] \
+         #[cfg(feature = \"a\")] \
+         #[must_use] \
+         fn f() {}",
+    )
+    .unwrap();
+    let resolved: Vec<Option<String>> = item
+        .attrs
+        .iter()
+        .map(|attr| {
+            cfg.resolve_attribute(attr)
+                .map(|attrs| attrs.concat().split_whitespace().collect())
+        })
+        .collect();
+
+    assert_eq!(
+        resolved,
+        [
+            Some(String::from("#[allow(unused)]#[inline]")),
+            Some(String::new()),
+            None,
+            Some(String::new()),
+            None,
+        ]
+    );
+    assert!(cfg.is_enabled(&item.attrs));
+    assert!(!make_cfg(&["feature=\"b\""]).is_enabled(&item.attrs));
+}
+
+const MAIN: &str = "use my_lib::f;\n\nfn main() {\n    println!(\"{}\", f());\n}\n";
+
+const NESTED_USE: &str = "\
+pub mod math;
+
+pub fn f() -> u64 {
+    #[cfg(feature = \"bigint\")]
+    use crate::math::gcd;
+    #[cfg(not(feature = \"bigint\"))]
+    fn gcd(a: u64, _: u64) -> u64 { a }
+    gcd(12, 8)
+}
+";
+
+const MATH: &str = "pub fn gcd(a: u64, b: u64) -> u64 { if b == 0 { a } else { gcd(b, a % b) } }\n";
+
+#[test]
+fn resolves_cfg_of_nested_use() {
+    let dir = make_library(
+        "nested-use",
+        &[("src/lib.rs", NESTED_USE), ("src/math.rs", MATH)],
+    );
+
+    let output = expunch(&dir, MAIN, &["feature=\"bigint\""]);
+    assert!(output.contains("usecrate::my_lib::math::gcd;"));
+    assert!(!output.contains("#[cfg(feature=\"bigint\")]"));
+    assert!(!output.contains("fngcd(a:u64,_:u64)"));
+
+    let output = expunch(&dir, MAIN, &["feature=\"other\""]);
+    assert!(!output.contains("usecrate::my_lib::math::gcd;"));
+    assert!(output.contains("fngcd(a:u64,_:u64)"));
+}
+
+const INNER: &str = "\
+pub mod a {
+    #![cfg_attr(feature = \"bigint\", allow(unused))]
+
+    pub fn f() -> u64 {
+        #![cfg_attr(feature = \"bigint\", allow(unused_variables))]
+        let x = 1;
+        2
+    }
+}
+
+pub use a::f;
+";
+
+#[test]
+fn keeps_style_of_inner_attributes() {
+    let dir = make_library("inner", &[("src/lib.rs", INNER)]);
+
+    let output = expunch(&dir, MAIN, &["feature=\"bigint\""]);
+    assert!(output.contains("pubmoda{#![allow(unused)]"));
+    assert!(output.contains("->u64{#![allow(unused_variables)]"));
+
+    let output = expunch(&dir, MAIN, &["feature=\"other\""]);
+    assert!(!output.contains("allow(unused"));
+    assert!(!output.contains("cfg_attr"));
+}
+
+const NESTED: &str = "\
+pub struct Seg {
+    pub n: u64,
+    #[cfg(feature = \"bigint\")]
+    pub big: u64,
+}
+
+pub enum Kind {
+    #[cfg(feature = \"bigint\")]
+    Big,
+    Small,
+}
+
+impl Seg {
+    #[cfg(feature = \"bigint\")]
+    pub fn big(&self) -> u64 {
+        self.big
+    }
+
+    pub fn kind(&self, kind: Kind) -> u64 {
+        match kind {
+            #[cfg(feature = \"bigint\")]
+            Kind::Big => 2,
+            Kind::Small => 1,
+        }
+    }
+}
+
+pub fn f() -> u64 {
+    let mut x = 1;
+    #[cfg(feature = \"bigint\")]
+    x += 10;
+    #[cfg(unix)]
+    x += 100;
+    x
+}
+";
+
+#[test]
+fn resolves_cfg_of_nested_positions() {
+    let dir = make_library("nested", &[("src/lib.rs", NESTED)]);
+
+    let output = expunch(&dir, MAIN, &["feature=\"bigint\""]);
+    assert!(output.contains("pubbig:u64,"));
+    assert!(output.contains("Big,"));
+    assert!(output.contains("pubfnbig(&self)"));
+    assert!(output.contains("Kind::Big=>2,"));
+    assert!(output.contains("letmutx=1;x+=10;"));
+    assert!(!output.contains("feature"));
+
+    let output = expunch(&dir, MAIN, &["feature=\"other\""]);
+    assert!(output.contains("pubn:u64,}"));
+    assert!(output.contains("enumKind{Small,}"));
+    assert!(!output.contains("pubfnbig(&self)"));
+    assert!(output.contains("matchkind{Kind::Small=>1,}"));
+    assert!(output.contains("letmutx=1;#[cfg(unix)]x+=100;"));
+    assert!(!output.contains("feature"));
+}
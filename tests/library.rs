@@ -0,0 +1,105 @@
+use cargo_expunch::expuncher::Expuncher;
+use std::fs;
+use std::path::PathBuf;
+
+/// 一時ディレクトリにファイルを作成する
+fn make_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "cargo-expunch-library-{}-{}",
+        name,
+        std::process::id()
+    ));
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    dir
+}
+
+const SORT: &str = "\
+use std::io;
+
+pub fn print() {
+    let _ = io::stdout();
+}
+
+pub mod nested {
+    pub fn read() -> u32 {
+        io::read()
+    }
+}
+";
+
+const MAIN: &str = "\
+mod out {
+    use std::io;
+
+    pub fn flush() {
+        let _ = io::stdout();
+    }
+}
+
+fn main() {
+    algos::sort::print();
+    println!(\"{}\", io::read());
+}
+";
+
+#[test]
+fn rewrites_only_dependencies_not_shadowed() {
+    let dir = make_files(
+        "shadowed",
+        &[
+            ("algos/src/lib.rs", "pub mod sort;\n"),
+            ("algos/src/sort.rs", SORT),
+            ("io/src/lib.rs", "pub fn read() -> u32 { 1 }\n"),
+        ],
+    );
+    let mut expuncher = Expuncher::new("algos", dir.join("algos/src/lib.rs"));
+    expuncher.add_library("io", dir.join("io/src/lib.rs"));
+    expuncher.set_library_dependencies("algos", &[String::from("io")]);
+    expuncher.set_library_dependencies("io", &[]);
+    expuncher.set_tree_shaking(false);
+    expuncher
+        .analyze_source_code(&dir.join("app/src/main.rs"), MAIN.to_string())
+        .unwrap();
+    let output = expuncher.to_string().unwrap();
+
+    // use文で定義された名前はライブラリクレートとして扱わない
+    assert!(output.contains("let _ = io::stdout();\n}\n\npub mod nested"));
+    assert!(!output.contains("crate::io::stdout"));
+    // インラインモジュールの内部では外側のuse文の名前は見えない
+    assert!(output.contains("crate::io::read()\n"));
+    assert!(output.contains("println!(\"{}\", io::read());"));
+    assert!(expuncher.diagnostics().is_empty());
+}
+
+#[test]
+fn ignores_libraries_that_are_not_dependencies() {
+    let dir = make_files(
+        "not-dependency",
+        &[
+            (
+                "algos/src/lib.rs",
+                "pub fn print() {\n    io::print();\n}\n",
+            ),
+            ("io/src/lib.rs", "pub fn read() -> u32 { 1 }\n"),
+        ],
+    );
+    let mut expuncher = Expuncher::new("algos", dir.join("algos/src/lib.rs"));
+    expuncher.add_library("io", dir.join("io/src/lib.rs"));
+    expuncher.set_library_dependencies("algos", &[]);
+    expuncher.set_tree_shaking(false);
+    expuncher
+        .analyze_source_code(
+            &dir.join("app/src/main.rs"),
+            String::from("fn main() {\n    algos::print();\n}\n"),
+        )
+        .unwrap();
+    let output = expuncher.to_string().unwrap();
+
+    assert!(output.contains("    io::print();"));
+    assert!(!output.contains("crate::io::print"));
+    assert!(expuncher.diagnostics().is_empty());
+}
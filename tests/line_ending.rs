@@ -0,0 +1,55 @@
+use cargo_expunch::expuncher::{Expuncher, Formatting, LineEnding};
+use std::fs;
+use std::path::PathBuf;
+
+/// 一時ディレクトリにCRLFのライブラリクレートを作成する
+fn make_library(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "cargo-expunch-line-ending-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let files = [
+        ("src/lib.rs", "pub mod a;\npub mod b {\n    pub mod c;\n}\n"),
+        ("src/a.rs", "pub fn f() -> u32 {\n    1\n}\n"),
+        ("src/b/c.rs", "pub fn g() -> u32 {\n    crate::a::f()\n}\n"),
+    ];
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content.replace('\n', "\r\n")).unwrap();
+    }
+    dir
+}
+
+const MAIN: &str = "use my_lib::b::c::g;\r\n\r\nfn main() {\r\n    println!(\"{}\", g());\r\n}\r\n";
+
+/// 展開したソースコードを取得する
+fn expunch(name: &str, formatting: Formatting, line_ending: Option<LineEnding>) -> String {
+    let dir = make_library(name);
+    let mut expuncher = Expuncher::new("my_lib", dir.join("src/lib.rs"));
+    expuncher.set_formatting(formatting);
+    if let Some(line_ending) = line_ending {
+        expuncher.set_line_ending(line_ending);
+    }
+    expuncher
+        .analyze_source_code(&dir.join("src/main.rs"), MAIN.to_string())
+        .unwrap();
+    expuncher.to_string().unwrap()
+}
+
+#[test]
+fn keeps_line_ending_of_source_files() {
+    for (name, formatting) in [("none", Formatting::None), ("indent", Formatting::Indent)] {
+        let output = expunch(name, formatting, None);
+        assert!(output.contains("mod my_lib {\r\n"));
+        assert_eq!(output.matches('\n').count(), output.matches("\r\n").count());
+    }
+}
+
+#[test]
+fn normalizes_line_ending() {
+    let output = expunch("normalize", Formatting::None, Some(LineEnding::Lf));
+    assert!(output.contains("mod my_lib {\n"));
+    assert!(!output.contains('\r'));
+}
@@ -0,0 +1,96 @@
+use cargo_expunch::expuncher::Expuncher;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 一時ディレクトリにライブラリクレートのファイルを作成する
+fn make_library(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cargo-expunch-{}-{}", name, std::process::id()));
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    dir
+}
+
+/// ライブラリクレートを参照するソースコードを展開する
+fn expunch(dir: &Path, main: &str) -> String {
+    let mut expuncher = Expuncher::new("my_lib", dir.join("src/lib.rs"));
+    expuncher
+        .analyze_source_code(&dir.join("src/main.rs"), main.to_string())
+        .unwrap();
+    expuncher.to_string().unwrap()
+}
+
+const GCD: &str = "\
+pub mod num;
+
+pub trait Gcd {
+    fn gcd(self, other: Self) -> Self;
+}
+
+impl Gcd for u64 {
+    fn gcd(self, other: Self) -> Self {
+        if other == 0 { self } else { other.gcd(self % other) }
+    }
+}
+
+pub fn helper() {}
+";
+
+const NUM: &str = "\
+pub trait Twice {
+    fn twice(self) -> Self;
+}
+
+impl Twice for u32 {
+    fn twice(self) -> Self {
+        self * 2
+    }
+}
+
+pub fn unused() {}
+";
+
+#[test]
+fn keeps_items_of_glob_imported_module() {
+    let dir = make_library("glob", &[("src/lib.rs", GCD), ("src/num.rs", NUM)]);
+    let output = expunch(
+        &dir,
+        "use my_lib::*;\n\nfn main() {\n    println!(\"{}\", 12u64.gcd(8));\n}\n",
+    );
+
+    assert!(output.contains("pub trait Gcd"));
+    assert!(output.contains("impl Gcd for u64"));
+    assert!(output.contains("pub fn helper"));
+    assert!(!output.contains("pub trait Twice"));
+    assert!(!output.contains("pub fn unused"));
+}
+
+#[test]
+fn keeps_items_of_nested_glob_import() {
+    let dir = make_library("nested-glob", &[("src/lib.rs", GCD), ("src/num.rs", NUM)]);
+    let output = expunch(
+        &dir,
+        "fn main() {\n    use my_lib::num::*;\n    println!(\"{}\", 3u32.twice());\n}\n",
+    );
+
+    assert!(output.contains("pub trait Twice"));
+    assert!(output.contains("impl Twice for u32"));
+    assert!(output.contains("pub fn unused"));
+    assert!(!output.contains("pub trait Gcd"));
+}
+
+#[test]
+fn keeps_trait_referenced_by_method_name() {
+    let dir = make_library("method", &[("src/lib.rs", GCD), ("src/num.rs", NUM)]);
+    let output = expunch(
+        &dir,
+        "use my_lib::Gcd as _;\n\nfn main() {\n    println!(\"{}\", 12u64.gcd(8));\n}\n",
+    );
+
+    assert!(output.contains("pub trait Gcd"));
+    assert!(output.contains("impl Gcd for u64"));
+    assert!(!output.contains("pub fn helper"));
+    assert!(!output.contains("pub trait Twice"));
+}